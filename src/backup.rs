@@ -0,0 +1,314 @@
+use crate::{elevate, helper::LogLevel, state, Result};
+
+use std::{
+    fs, io,
+    os::unix::fs::symlink as symlink_unix,
+    path::{Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+/// A timestamped directory under `$XDG_STATE_HOME/tread/backups` that
+/// conflicting files are moved into, keeping their original absolute paths.
+/// The directory is only created once the first file is backed up.
+///
+/// Conflicts of privileged entries are moved by root, so they go into a
+/// directory of the same name under `privileged-backups` that is only ever
+/// touched with elevation.
+#[derive(Default)]
+pub struct BackupSet {
+    id: Option<String>,
+}
+
+impl BackupSet {
+    pub fn stash(&mut self, path: &Path, dry: bool) -> Result<()> {
+        let backup = self.backup_path(path, false)?;
+
+        if !dry {
+            let backup_parent = backup.parent().ok_or(format!(
+                "could not access parent dir of {}",
+                backup.display()
+            ))?;
+            fs::create_dir_all(backup_parent).map_err(|err| {
+                format!("could not create dir {}: {err}", backup_parent.display())
+            })?;
+            move_path(path, &backup)?;
+        }
+
+        log!(Info, "Backed up {} to {}", path.display(), backup.display());
+        Ok(())
+    }
+
+    /// Where `path` is kept in this backup set.
+    pub fn backup_path(&mut self, path: &Path, privileged: bool) -> Result<PathBuf> {
+        let id = match &self.id {
+            Some(id) => id.clone(),
+            None => {
+                let id = new_backup_set_id()?;
+                self.id = Some(id.clone());
+                id
+            }
+        };
+        let root = backups_dir(privileged)?.join(id);
+        Ok(root.join(path.strip_prefix("/").unwrap_or(path)))
+    }
+
+    /// Creates the directory privileged backups are moved into, so that the
+    /// user still owns everything up to it.
+    pub fn create_privileged_root(&self) -> Result<()> {
+        if let Some(id) = &self.id {
+            let root = backups_dir(true)?.join(id);
+            fs::create_dir_all(&root)
+                .map_err(|err| format!("could not create dir {}: {err}", root.display()))?;
+        }
+        Ok(())
+    }
+}
+
+pub fn backups_dir(privileged: bool) -> Result<PathBuf> {
+    Ok(state::state_dir()?.join(match privileged {
+        true => "privileged-backups",
+        false => "backups",
+    }))
+}
+
+/// Backup set ids, oldest first.
+pub fn list() -> Result<Vec<String>> {
+    let mut ids = Vec::new();
+    for privileged in [false, true] {
+        let backups_dir = backups_dir(privileged)?;
+        if !backups_dir.exists() {
+            continue;
+        }
+        for entry in fs::read_dir(&backups_dir)
+            .map_err(|err| format!("could not read dir {}: {err}", backups_dir.display()))?
+        {
+            let entry = entry?;
+            if entry.path().is_dir() {
+                ids.push(entry.file_name().to_string_lossy().to_string());
+            }
+        }
+    }
+    ids.sort();
+    ids.dedup();
+    Ok(ids)
+}
+
+/// Moves every file of a backup set back to where it came from. Symlinks
+/// occupying the original location are replaced, anything else is left alone.
+/// Privileged backups are moved back by `batch`.
+pub fn restore(
+    id: Option<&str>,
+    dry: bool,
+    state: &mut state::State,
+    batch: &mut elevate::Batch,
+) -> Result<()> {
+    let ids = list()?;
+    let id = match id {
+        Some(id) if ids.iter().any(|other| other == id) => id,
+        Some(id) => return Err(format!("could not find backup {id}").into()),
+        None => ids.last().ok_or("could not find any backups".to_string())?,
+    };
+
+    let root = backups_dir(false)?.join(id);
+    if root.exists() {
+        restore_dir(&root, Path::new("/"), dry, state, None)?;
+        if !dry && fs::read_dir(&root)?.next().is_none() {
+            fs::remove_dir(&root)
+                .map_err(|err| format!("could not remove dir {}: {err}", root.display()))?;
+        }
+    }
+    let root = backups_dir(true)?.join(id);
+    if root.exists() {
+        restore_dir(&root, Path::new("/"), dry, state, Some(batch))?;
+        if !dry {
+            batch.remove_dir(&root);
+        }
+    }
+
+    log!(Info, "Restored backup {id}");
+    Ok(())
+}
+
+fn restore_dir(
+    backup_dir: &Path,
+    original_dir: &Path,
+    dry: bool,
+    state: &mut state::State,
+    mut batch: Option<&mut elevate::Batch>,
+) -> Result<()> {
+    for entry in fs::read_dir(backup_dir)
+        .map_err(|err| format!("could not read dir {}: {err}", backup_dir.display()))?
+    {
+        let entry = entry?;
+        let backup = entry.path();
+        let original = original_dir.join(entry.file_name());
+
+        if original.is_symlink() {
+            log!(
+                Warning,
+                "Destination {} is a symlink. Removing",
+                original.display()
+            );
+            if !dry {
+                match batch.as_deref_mut() {
+                    Some(batch) => batch.remove(&original),
+                    None => fs::remove_file(&original).map_err(|err| {
+                        format!("could not remove file {}: {err}", original.display())
+                    })?,
+                }
+                state.forget(&original);
+            }
+        } else if original.exists() {
+            if original.is_dir() && fs::symlink_metadata(&backup)?.is_dir() {
+                restore_dir(&backup, &original, dry, state, batch.as_deref_mut())?;
+                if !dry {
+                    match batch.as_deref_mut() {
+                        // Only empty once the batch has moved everything back.
+                        Some(batch) => batch.remove_dir(&backup),
+                        None if fs::read_dir(&backup)?.next().is_none() => fs::remove_dir(&backup)
+                            .map_err(|err| {
+                                format!("could not remove dir {}: {err}", backup.display())
+                            })?,
+                        None => {}
+                    }
+                }
+            } else {
+                log!(
+                    Warning,
+                    "Destination {} already exists. Resolve manually",
+                    original.display()
+                );
+            }
+            continue;
+        }
+
+        if !dry {
+            match batch.as_deref_mut() {
+                Some(batch) => batch.move_path(&backup, &original)?,
+                None => {
+                    fs::create_dir_all(original_dir).map_err(|err| {
+                        format!("could not create dir {}: {err}", original_dir.display())
+                    })?;
+                    move_path(&backup, &original)?;
+                }
+            }
+        }
+        log!(Info, "Restored {}", original.display());
+    }
+    Ok(())
+}
+
+/// Renames `from` to `to`, falling back to copy and delete when they live on
+/// different filesystems.
+pub fn move_path(from: &Path, to: &Path) -> Result<()> {
+    match fs::rename(from, to) {
+        Ok(()) => Ok(()),
+        Err(err) if err.kind() == io::ErrorKind::CrossesDevices => {
+            copy_all(from, to)?;
+            if fs::symlink_metadata(from)?.is_dir() {
+                fs::remove_dir_all(from)
+            } else {
+                fs::remove_file(from)
+            }
+            .map_err(|err| format!("could not remove {}: {err}", from.display()).into())
+        }
+        Err(err) => Err(format!(
+            "could not move {} to {}: {err}",
+            from.display(),
+            to.display()
+        )
+        .into()),
+    }
+}
+
+/// Copies `from` to `to`, recursively if it is a directory. Symlinks are
+/// copied as they are.
+pub fn copy_all(from: &Path, to: &Path) -> Result<()> {
+    let metadata = fs::symlink_metadata(from)?;
+    if metadata.is_symlink() {
+        symlink_unix(fs::read_link(from)?, to)
+            .map_err(|err| format!("could not symlink {}: {err}", to.display()))?;
+    } else if metadata.is_dir() {
+        fs::create_dir(to)
+            .map_err(|err| format!("could not create dir {}: {err}", to.display()))?;
+        for entry in fs::read_dir(from)? {
+            let entry = entry?;
+            copy_all(&entry.path(), &to.join(entry.file_name()))?;
+        }
+    } else {
+        fs::copy(from, to).map_err(|err| {
+            format!(
+                "could not copy {} to {}: {err}",
+                from.display(),
+                to.display()
+            )
+        })?;
+    }
+    Ok(())
+}
+
+/// A new id, not taken by any backup set yet.
+fn new_backup_set_id() -> Result<String> {
+    let taken = list()?;
+    let timestamp = timestamp();
+    let mut id = timestamp.clone();
+    let mut suffix = 1;
+    while taken.contains(&id) {
+        id = format!("{timestamp}-{suffix}");
+        suffix += 1;
+    }
+    Ok(id)
+}
+
+/// Current UTC time as `YYYY-MM-DDTHH-MM-SS`, which sorts chronologically.
+fn timestamp() -> String {
+    format_timestamp(
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|duration| duration.as_secs())
+            .unwrap_or_default(),
+    )
+}
+
+fn format_timestamp(secs: u64) -> String {
+    let (days, rem) = ((secs / 86400) as i64, secs % 86400);
+
+    // days since epoch to civil date, see https://howardhinnant.github.io/date_algorithms.html
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+
+    format!(
+        "{year:04}-{month:02}-{day:02}T{:02}-{:02}-{:02}",
+        rem / 3600,
+        rem % 3600 / 60,
+        rem % 60
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn formats_timestamps_as_utc() {
+        assert_eq!(format_timestamp(0), "1970-01-01T00-00-00");
+        assert_eq!(format_timestamp(951782400), "2000-02-29T00-00-00");
+        assert_eq!(format_timestamp(1709251199), "2024-02-29T23-59-59");
+        assert_eq!(format_timestamp(4107542400), "2100-03-01T00-00-00");
+    }
+
+    #[test]
+    fn timestamps_sort_chronologically() {
+        let earlier = format_timestamp(1_000_000_000);
+        let later = format_timestamp(1_700_000_000);
+        assert!(earlier < later);
+        assert_eq!(timestamp().len(), earlier.len());
+    }
+}
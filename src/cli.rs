@@ -8,6 +8,7 @@ use std::process::exit;
 #[derive(Debug)]
 pub struct Cli {
     pub manifest_path: PathBuf,
+    pub profile: Option<String>,
     pub subcommand: SubCommand,
 }
 
@@ -15,18 +16,65 @@ pub struct Cli {
 pub enum SubCommand {
     Sync {
         force: bool,
+        backup: bool,
+        interactive: bool,
         dry: bool,
+        prune: bool,
+        jobs: Option<usize>,
         name: Option<String>,
     },
     Link {
         force: bool,
+        backup: bool,
+        interactive: bool,
         dry: bool,
         name: Option<String>,
     },
     Generate {
         dry: bool,
+        diff: bool,
         name: Option<String>,
     },
+    Unlink {
+        dry: bool,
+        name: Option<String>,
+    },
+    Prune {
+        dry: bool,
+    },
+    Restore {
+        list: bool,
+        dry: bool,
+        id: Option<String>,
+    },
+    Adopt {
+        path: PathBuf,
+        name: Option<String>,
+        dry: bool,
+    },
+    Status {
+        name: Option<String>,
+    },
+    Diff {
+        name: Option<String>,
+    },
+    Manifest,
+}
+
+impl SubCommand {
+    pub fn is_dry(&self) -> bool {
+        match self {
+            SubCommand::Sync { dry, .. }
+            | SubCommand::Link { dry, .. }
+            | SubCommand::Generate { dry, .. }
+            | SubCommand::Unlink { dry, .. }
+            | SubCommand::Prune { dry }
+            | SubCommand::Restore { dry, .. }
+            | SubCommand::Adopt { dry, .. } => *dry,
+            // Only read the destinations, there is nothing to save.
+            SubCommand::Status { .. } | SubCommand::Diff { .. } | SubCommand::Manifest => true,
+        }
+    }
 }
 
 const USAGE: &str = "
@@ -34,40 +82,112 @@ Usage: tread [OPTION] <SUBCOMMAND>
 
 Options:
     -m, --manifest <FILE>  Path to Manifest file [default: ./Manifest.toml]
+        --profile <NAME>   Apply the overrides in [profiles.NAME]
     -h, --help             Print help
 
 Subcommands:
     sync      Symlink files and generate templates 
     link      Symlink files
-    generate  Generate templates";
+    generate  Generate templates
+    unlink    Remove symlinks
+    prune     Remove symlinks and templates no longer in the manifest
+    restore   Restore files moved away by --backup
+    adopt     Move an existing file into the dotfiles and link it
+    status    Show whether every destination is up to date
+    diff      Show what generating templates would change
+    manifest  Print the manifest as it applies to this machine";
 
 const SYNC_USAGE: &str = "
 Usage: tread sync [OPTION] [NAME]
 
 Options:
-    -f, --force  Force remove existing files
-    -d, --dry    Dry run without actually creating the symlinks or
-                 generating any templates
-    -h, --help   Print help";
+    -f, --force        Force remove existing files
+    -b, --backup       Move existing files to a backup instead of removing them
+    -i, --interactive  Ask how to resolve each conflicting file
+    -p, --prune        Remove symlinks and templates no longer in the manifest
+    -j, --jobs <N>     Number of entries to process at once
+                       [default: number of CPUs, 1 with --interactive]
+    -d, --dry          Dry run without actually creating the symlinks or
+                       generating any templates
+    -h, --help         Print help";
 
 const LINK_USAGE: &str = "
 Usage: tread link [OPTION] [NAME]
 
 Options:
-    -f, --force  Force remove existing files
-    -d, --dry    Dry run without actually creating the symlinks
-    -h, --help   Print help";
+    -f, --force        Force remove existing files
+    -b, --backup       Move existing files to a backup instead of removing them
+    -i, --interactive  Ask how to resolve each conflicting file
+    -d, --dry          Dry run without actually creating the symlinks
+    -h, --help         Print help";
+
+const UNLINK_USAGE: &str = "
+Usage: tread unlink [OPTION] [NAME]
+
+Options:
+    -d, --dry   Dry run without actually removing the symlinks
+    -h, --help  Print help";
+
+const PRUNE_USAGE: &str = "
+Usage: tread prune [OPTION]
+
+Options:
+    -d, --dry   Dry run without actually removing anything
+    -h, --help  Print help";
+
+const RESTORE_USAGE: &str = "
+Usage: tread restore [OPTION] [ID]
+
+Restores the most recent backup if no ID is given.
+
+Options:
+    -l, --list  List available backups
+    -d, --dry   Dry run without actually restoring any files
+    -h, --help  Print help";
+
+const ADOPT_USAGE: &str = "
+Usage: tread adopt [OPTION] <PATH>
+
+Options:
+    -n, --name <NAME>  Name of the new entry [default: file name of PATH]
+    -d, --dry          Dry run without actually moving any files
+    -h, --help         Print help";
+
+const STATUS_USAGE: &str = "
+Usage: tread status [NAME]
+
+Exits with a non-zero status if any destination is out of sync.
+
+Options:
+    -h, --help  Print help";
+
+const DIFF_USAGE: &str = "
+Usage: tread diff [NAME]
+
+Options:
+    -h, --help  Print help";
+
+const MANIFEST_USAGE: &str = "
+Usage: tread manifest
+
+Prints the manifest with the overrides in [hosts.HOSTNAME] and the selected
+profile applied.
+
+Options:
+    -h, --help  Print help";
 
 const GENERATE_USAGE: &str = "
-Usage: tread generate [NAME]
+Usage: tread generate [OPTION] [NAME]
 
 Options:
     -d, --dry   Dry run without actually generating any templates
+        --diff  Show what changes before writing each template
     -h, --help  Print help";
 
 impl Cli {
     pub fn try_parse() -> Result<Self> {
         let mut manifest_path = PathBuf::from("Manifest.toml");
+        let mut profile: Option<String> = None;
         let mut subcommand: Option<SubCommand> = None;
 
         let mut lexer = Lexer::new(std::env::args_os());
@@ -85,10 +205,26 @@ impl Cli {
                         return Err(format!("missing required argument: FILE\n{USAGE}").into());
                     }
                 }
+                LongFlag("profile") => {
+                    if let Some(val) = lexer.get_value() {
+                        profile = Some(val.into_string().map_err(|err| {
+                            format!(
+                                "Unexpected argument in {}",
+                                String::from_utf8_lossy(err.as_os_str().as_bytes())
+                            )
+                        })?)
+                    } else {
+                        return Err(format!("missing required argument: NAME\n{USAGE}").into());
+                    }
+                }
                 Value(ref val) => match val.as_os_str().as_bytes() {
                     b"sync" => {
                         let mut force = false;
+                        let mut backup = false;
+                        let mut interactive = false;
                         let mut dry = false;
+                        let mut prune = false;
+                        let mut jobs: Option<usize> = None;
                         let mut name: Option<String> = None;
                         while let Some(arg) = lexer.next_token()? {
                             match arg {
@@ -97,6 +233,27 @@ impl Cli {
                                     exit(0);
                                 }
                                 ShortFlag('f') | LongFlag("force") => force = true,
+                                ShortFlag('b') | LongFlag("backup") => backup = true,
+                                ShortFlag('i') | LongFlag("interactive") => interactive = true,
+                                ShortFlag('p') | LongFlag("prune") => prune = true,
+                                ShortFlag('j') | LongFlag("jobs") => {
+                                    if let Some(val) = lexer.get_value() {
+                                        jobs = Some(
+                                            val.to_str()
+                                                .and_then(|val| val.parse().ok())
+                                                .filter(|jobs| *jobs > 0)
+                                                .ok_or(format!(
+                                                    "invalid number of jobs {}\n{SYNC_USAGE}",
+                                                    val.to_string_lossy()
+                                                ))?,
+                                        );
+                                    } else {
+                                        return Err(format!(
+                                            "missing required argument: N\n{SYNC_USAGE}"
+                                        )
+                                        .into());
+                                    }
+                                }
                                 ShortFlag('d') | LongFlag("dry") => dry = true,
                                 Value(val) => {
                                     name = Some(val.into_string().map_err(|err| {
@@ -111,10 +268,20 @@ impl Cli {
                                 }
                             }
                         }
-                        subcommand = Some(SubCommand::Sync { force, dry, name });
+                        subcommand = Some(SubCommand::Sync {
+                            force,
+                            backup,
+                            interactive,
+                            dry,
+                            prune,
+                            jobs,
+                            name,
+                        });
                     }
                     b"link" => {
                         let mut force = false;
+                        let mut backup = false;
+                        let mut interactive = false;
                         let mut dry = false;
                         let mut name: Option<String> = None;
                         while let Some(arg) = lexer.next_token()? {
@@ -124,6 +291,8 @@ impl Cli {
                                     exit(0);
                                 }
                                 ShortFlag('f') | LongFlag("force") => force = true,
+                                ShortFlag('b') | LongFlag("backup") => backup = true,
+                                ShortFlag('i') | LongFlag("interactive") => interactive = true,
                                 ShortFlag('d') | LongFlag("dry") => dry = true,
                                 Value(val) => {
                                     name = Some(val.into_string().map_err(|err| {
@@ -138,10 +307,17 @@ impl Cli {
                                 }
                             }
                         }
-                        subcommand = Some(SubCommand::Link { force, name, dry });
+                        subcommand = Some(SubCommand::Link {
+                            force,
+                            backup,
+                            interactive,
+                            dry,
+                            name,
+                        });
                     }
                     b"generate" => {
                         let mut dry = false;
+                        let mut diff = false;
                         let mut name: Option<String> = None;
                         while let Some(arg) = lexer.next_token()? {
                             match arg {
@@ -150,6 +326,7 @@ impl Cli {
                                     exit(0);
                                 }
                                 ShortFlag('d') | LongFlag("dry") => dry = true,
+                                LongFlag("diff") => diff = true,
                                 Value(val) => {
                                     name = Some(val.into_string().map_err(|err| {
                                         format!(
@@ -165,7 +342,195 @@ impl Cli {
                                 }
                             }
                         }
-                        subcommand = Some(SubCommand::Generate { dry, name });
+                        subcommand = Some(SubCommand::Generate { dry, diff, name });
+                    }
+                    b"unlink" => {
+                        let mut dry = false;
+                        let mut name: Option<String> = None;
+                        while let Some(arg) = lexer.next_token()? {
+                            match arg {
+                                ShortFlag('h') | LongFlag("help") => {
+                                    println!("Remove symlinks\n{UNLINK_USAGE}");
+                                    exit(0);
+                                }
+                                ShortFlag('d') | LongFlag("dry") => dry = true,
+                                Value(val) => {
+                                    name = Some(val.into_string().map_err(|err| {
+                                        format!(
+                                            "Unexpected argument in {}",
+                                            String::from_utf8_lossy(err.as_os_str().as_bytes())
+                                        )
+                                    })?)
+                                }
+                                _ => {
+                                    return Err(
+                                        format!("invalid option {arg}\n{UNLINK_USAGE}").into()
+                                    )
+                                }
+                            }
+                        }
+                        subcommand = Some(SubCommand::Unlink { dry, name });
+                    }
+                    b"prune" => {
+                        let mut dry = false;
+                        while let Some(arg) = lexer.next_token()? {
+                            match arg {
+                                ShortFlag('h') | LongFlag("help") => {
+                                    println!(
+                                        "Remove symlinks and templates no longer in the manifest\n{PRUNE_USAGE}"
+                                    );
+                                    exit(0);
+                                }
+                                ShortFlag('d') | LongFlag("dry") => dry = true,
+                                _ => {
+                                    return Err(
+                                        format!("invalid option {arg}\n{PRUNE_USAGE}").into()
+                                    )
+                                }
+                            }
+                        }
+                        subcommand = Some(SubCommand::Prune { dry });
+                    }
+                    b"restore" => {
+                        let mut list = false;
+                        let mut dry = false;
+                        let mut id: Option<String> = None;
+                        while let Some(arg) = lexer.next_token()? {
+                            match arg {
+                                ShortFlag('h') | LongFlag("help") => {
+                                    println!(
+                                        "Restore files moved away by --backup\n{RESTORE_USAGE}"
+                                    );
+                                    exit(0);
+                                }
+                                ShortFlag('l') | LongFlag("list") => list = true,
+                                ShortFlag('d') | LongFlag("dry") => dry = true,
+                                Value(val) => {
+                                    id = Some(val.into_string().map_err(|err| {
+                                        format!(
+                                            "Unexpected argument in {}",
+                                            String::from_utf8_lossy(err.as_os_str().as_bytes())
+                                        )
+                                    })?)
+                                }
+                                _ => {
+                                    return Err(
+                                        format!("invalid option {arg}\n{RESTORE_USAGE}").into()
+                                    )
+                                }
+                            }
+                        }
+                        subcommand = Some(SubCommand::Restore { list, dry, id });
+                    }
+                    b"adopt" => {
+                        let mut path: Option<PathBuf> = None;
+                        let mut name: Option<String> = None;
+                        let mut dry = false;
+                        while let Some(arg) = lexer.next_token()? {
+                            match arg {
+                                ShortFlag('h') | LongFlag("help") => {
+                                    println!(
+                                        "Move an existing file into the dotfiles and link it\n{ADOPT_USAGE}"
+                                    );
+                                    exit(0);
+                                }
+                                ShortFlag('n') | LongFlag("name") => {
+                                    if let Some(val) = lexer.get_value() {
+                                        name = Some(val.into_string().map_err(|err| {
+                                            format!(
+                                                "Unexpected argument in {}",
+                                                String::from_utf8_lossy(err.as_os_str().as_bytes())
+                                            )
+                                        })?)
+                                    } else {
+                                        return Err(format!(
+                                            "missing required argument: NAME\n{ADOPT_USAGE}"
+                                        )
+                                        .into());
+                                    }
+                                }
+                                ShortFlag('d') | LongFlag("dry") => dry = true,
+                                Value(val) => path = Some(PathBuf::from(val)),
+                                _ => {
+                                    return Err(
+                                        format!("invalid option {arg}\n{ADOPT_USAGE}").into()
+                                    )
+                                }
+                            }
+                        }
+                        let path =
+                            path.ok_or(format!("missing required argument: PATH\n{ADOPT_USAGE}"))?;
+                        subcommand = Some(SubCommand::Adopt { path, name, dry });
+                    }
+                    b"status" => {
+                        let mut name: Option<String> = None;
+                        while let Some(arg) = lexer.next_token()? {
+                            match arg {
+                                ShortFlag('h') | LongFlag("help") => {
+                                    println!(
+                                        "Show whether every destination is up to date\n{STATUS_USAGE}"
+                                    );
+                                    exit(0);
+                                }
+                                Value(val) => {
+                                    name = Some(val.into_string().map_err(|err| {
+                                        format!(
+                                            "Unexpected argument in {}",
+                                            String::from_utf8_lossy(err.as_os_str().as_bytes())
+                                        )
+                                    })?)
+                                }
+                                _ => {
+                                    return Err(
+                                        format!("invalid option {arg}\n{STATUS_USAGE}").into()
+                                    )
+                                }
+                            }
+                        }
+                        subcommand = Some(SubCommand::Status { name });
+                    }
+                    b"diff" => {
+                        let mut name: Option<String> = None;
+                        while let Some(arg) = lexer.next_token()? {
+                            match arg {
+                                ShortFlag('h') | LongFlag("help") => {
+                                    println!(
+                                        "Show what generating templates would change\n{DIFF_USAGE}"
+                                    );
+                                    exit(0);
+                                }
+                                Value(val) => {
+                                    name = Some(val.into_string().map_err(|err| {
+                                        format!(
+                                            "Unexpected argument in {}",
+                                            String::from_utf8_lossy(err.as_os_str().as_bytes())
+                                        )
+                                    })?)
+                                }
+                                _ => {
+                                    return Err(format!("invalid option {arg}\n{DIFF_USAGE}").into())
+                                }
+                            }
+                        }
+                        subcommand = Some(SubCommand::Diff { name });
+                    }
+                    b"manifest" => {
+                        if let Some(arg) = lexer.next_token()? {
+                            match arg {
+                                ShortFlag('h') | LongFlag("help") => {
+                                    println!(
+                                        "Print the manifest as it applies to this machine\n{MANIFEST_USAGE}"
+                                    );
+                                    exit(0);
+                                }
+                                _ => {
+                                    return Err(
+                                        format!("invalid option {arg}\n{MANIFEST_USAGE}").into()
+                                    )
+                                }
+                            }
+                        }
+                        subcommand = Some(SubCommand::Manifest);
                     }
                     _ => return Err(format!("invalid subcommand {arg}\n{USAGE}").into()),
                 },
@@ -176,6 +541,7 @@ impl Cli {
         if let Some(subcommand) = subcommand {
             Ok(Cli {
                 manifest_path,
+                profile,
                 subcommand,
             })
         } else {
@@ -67,7 +67,7 @@ pub fn generate_material_colors(
         }
     }
 
-    generate_terminal_ansi_colors(context, color);
+    generate_terminal_ansi_colors(&mut context.values, color);
     context.insert("theme".to_string(), theme.to_string());
     Ok(())
 }
@@ -0,0 +1,48 @@
+use crate::Result;
+
+use similar::{ChangeTag, TextDiff};
+use std::{fs, path::Path};
+
+/// Prints a colorized unified diff of what writing `new` to `dest` would
+/// change. Returns whether there is any difference at all.
+pub fn print_file_diff(dest: &Path, new: &str) -> Result<bool> {
+    let old = if dest.exists() {
+        let data = fs::read(dest)
+            .map_err(|err| format!("could not read file {}: {err}", dest.display()))?;
+        String::from_utf8_lossy(&data).into_owned()
+    } else {
+        String::new()
+    };
+    if old == new {
+        return Ok(false);
+    }
+
+    let old_label = if dest.exists() {
+        dest.display().to_string()
+    } else {
+        "/dev/null".to_string()
+    };
+    println!("\x1b[1m--- {old_label}\x1b[0m");
+    println!("\x1b[1m+++ {}\x1b[0m", dest.display());
+
+    let diff = TextDiff::from_lines(old.as_str(), new);
+    for hunk in diff.unified_diff().iter_hunks() {
+        println!("\x1b[0;36m{}\x1b[0m", hunk.header());
+        for change in hunk.iter_changes() {
+            let (sign, color) = match change.tag() {
+                ChangeTag::Delete => ('-', "\x1b[0;31m"),
+                ChangeTag::Insert => ('+', "\x1b[0;32m"),
+                ChangeTag::Equal => (' ', "\x1b[0m"),
+            };
+            let line = change.value();
+            println!(
+                "{color}{sign}{}\x1b[0m",
+                line.strip_suffix('\n').unwrap_or(line)
+            );
+            if change.missing_newline() {
+                println!("\\ No newline at end of file");
+            }
+        }
+    }
+    Ok(true)
+}
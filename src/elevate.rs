@@ -0,0 +1,420 @@
+use crate::{execute_hook, helper::LogLevel, state, tmp_path, write_destination, Result};
+
+use std::{
+    env,
+    ffi::{OsStr, OsString},
+    fs,
+    io::Write,
+    os::unix::{
+        ffi::{OsStrExt, OsStringExt},
+        fs::{OpenOptionsExt, PermissionsExt},
+    },
+    path::{Path, PathBuf},
+    process,
+};
+
+/// Filesystem operations of privileged entries. They are collected while the
+/// entries are processed and carried out at the end by a single shell running
+/// under the elevation command, so credentials are only asked for once.
+///
+/// Along with each operation the batch keeps the commands undoing it. The
+/// script records how far it got, so that a failure halfway undoes exactly
+/// the operations that were carried out.
+#[derive(Default)]
+pub struct Batch {
+    script: Vec<u8>,
+    /// Commands undoing each operation of the script, in the same order.
+    undo: Vec<Vec<u8>>,
+    /// Rendered templates waiting to be copied into place.
+    staged: Vec<PathBuf>,
+    /// Post-hooks of privileged entries, which expect their files in place.
+    hooks: Vec<String>,
+    /// Whether privileged operations took effect that can't be undone
+    /// anymore.
+    committed: bool,
+}
+
+impl Batch {
+    /// Removes a file, or a directory with everything in it.
+    pub fn remove(&mut self, the_path: &Path) {
+        let saved = self.saved_path();
+        let the_path = the_path.as_os_str();
+        push_line(
+            &mut self.script,
+            "if [ -e {} ] || [ -L {} ]; then mv -- {} {}; fi",
+            &[the_path, the_path, the_path, &saved],
+        );
+        let mut undo = Vec::new();
+        push_undo(
+            &mut undo,
+            "if [ -e {} ] || [ -L {} ]; then mv -f -- {} {}; fi",
+            &[&saved, &saved, &saved, the_path],
+        );
+        self.done(undo);
+    }
+
+    /// Removes a directory if it is empty by the time the batch runs.
+    pub fn remove_dir(&mut self, the_path: &Path) {
+        push_line(
+            &mut self.script,
+            "rmdir -- {} 2>/dev/null || :",
+            &[the_path.as_os_str()],
+        );
+        let mut undo = Vec::new();
+        push_undo(
+            &mut undo,
+            "[ -d {} ] || mkdir -- {}",
+            &[the_path.as_os_str(), the_path.as_os_str()],
+        );
+        self.done(undo);
+    }
+
+    pub fn create_dir(&mut self, the_path: &Path) {
+        push_line(&mut self.script, "mkdir -- {}", &[the_path.as_os_str()]);
+        let mut undo = Vec::new();
+        push_undo(
+            &mut undo,
+            "[ ! -d {} ] || rmdir -- {}",
+            &[the_path.as_os_str(), the_path.as_os_str()],
+        );
+        self.done(undo);
+    }
+
+    pub fn create_dir_all(&mut self, the_path: &Path) {
+        push_line(&mut self.script, "mkdir -p -- {}", &[the_path.as_os_str()]);
+        let mut undo = Vec::new();
+        for dir in the_path.ancestors().take_while(|dir| !dir.exists()) {
+            push_undo(
+                &mut undo,
+                "[ ! -d {} ] || rmdir -- {}",
+                &[dir.as_os_str(), dir.as_os_str()],
+            );
+        }
+        self.done(undo);
+    }
+
+    pub fn symlink(&mut self, original: &Path, link: &Path) {
+        push_line(
+            &mut self.script,
+            "ln -s -- {} {}",
+            &[original.as_os_str(), link.as_os_str()],
+        );
+        let mut undo = Vec::new();
+        push_undo(
+            &mut undo,
+            "[ ! -L {} ] || rm -f -- {}",
+            &[link.as_os_str(), link.as_os_str()],
+        );
+        self.done(undo);
+    }
+
+    pub fn hard_link(&mut self, original: &Path, link: &Path) {
+        push_line(
+            &mut self.script,
+            "ln -- {} {}",
+            &[original.as_os_str(), link.as_os_str()],
+        );
+        let mut undo = Vec::new();
+        push_undo(&mut undo, "rm -f -- {}", &[link.as_os_str()]);
+        self.done(undo);
+    }
+
+    /// Stages `contents` in a private temporary file and queues writing it
+    /// to `dest`, the same way `write_atomic` does: into a temporary file
+    /// next to `dest` that then replaces it. The file gets `mode` before it
+    /// is in place, an existing `dest` otherwise keeps its permissions. It
+    /// also keeps its owner, and a symlinked one is written through.
+    pub fn write(&mut self, dest: &Path, contents: &[u8], mode: Option<u32>) -> Result<()> {
+        let staged = self.stage(contents)?;
+        let saved = self.saved_path();
+        let dest = write_destination(dest);
+        let tmp_path = tmp_path(&dest)?;
+        let [dest, tmp_path] = [dest.as_os_str(), tmp_path.as_os_str()];
+        push_line(
+            &mut self.script,
+            "if [ -e {} ]; then cp -p -- {} {}; fi",
+            &[dest, dest, &saved],
+        );
+        // Copying `dest` first is what carries its permissions and owner over.
+        let mut code = String::from(
+            "{ if [ -e {} ]; then cp -p -- {} {}; else rm -f -- {}; fi && cat -- {} > {}",
+        );
+        let mut args = vec![dest, dest, tmp_path, tmp_path, staged.as_os_str(), tmp_path];
+        let mode = mode.map(|mode| OsString::from(format!("{mode:04o}")));
+        if let Some(mode) = &mode {
+            code.push_str(" && chmod {} -- {}");
+            args.extend([mode.as_os_str(), tmp_path]);
+        }
+        // Nothing is left behind next to `dest` when this fails.
+        code.push_str(" && mv -f -- {} {}; } || { rm -f -- {}; exit 1; }");
+        args.extend([tmp_path, dest, tmp_path]);
+        push_line(&mut self.script, &code, &args);
+        let mut undo = Vec::new();
+        push_undo(
+            &mut undo,
+            "if [ -e {} ]; then mv -f -- {} {}; else rm -f -- {}; fi",
+            &[&saved, &saved, dest, dest],
+        );
+        self.done(undo);
+        Ok(())
+    }
+
+    pub fn set_permissions(&mut self, the_path: &Path, mode: u32) {
+        let mode = OsString::from(format!("{mode:04o}"));
+        push_line(
+            &mut self.script,
+            "chmod {} -- {}",
+            &[&mode, the_path.as_os_str()],
+        );
+        // A path the batch creates itself is removed again anyway.
+        let mut undo = Vec::new();
+        if let Ok(metadata) = fs::metadata(the_path) {
+            let previous =
+                OsString::from(format!("{:04o}", metadata.permissions().mode() & 0o7777));
+            push_undo(
+                &mut undo,
+                "[ ! -e {} ] || chmod {} -- {}",
+                &[the_path.as_os_str(), &previous, the_path.as_os_str()],
+            );
+        }
+        self.done(undo);
+    }
+
+    /// Moves `the_path` to `backup`, creating the directories it lives in.
+    /// Moves `from` to `to`, creating the missing parent dirs of `to`.
+    pub fn move_path(&mut self, from: &Path, to: &Path) -> Result<()> {
+        let to_parent = to
+            .parent()
+            .ok_or(format!("could not access parent dir of {}", to.display()))?;
+        push_line(&mut self.script, "mkdir -p -- {}", &[to_parent.as_os_str()]);
+        push_line(
+            &mut self.script,
+            "mv -- {} {}",
+            &[from.as_os_str(), to.as_os_str()],
+        );
+        let mut undo = Vec::new();
+        push_undo(
+            &mut undo,
+            "mv -- {} {}",
+            &[to.as_os_str(), from.as_os_str()],
+        );
+        self.done(undo);
+        Ok(())
+    }
+
+    /// Where the operation being queued keeps what it replaces.
+    fn saved_path(&self) -> OsString {
+        saved_dir()
+            .join(self.undo.len().to_string())
+            .into_os_string()
+    }
+
+    /// Ends the operation being queued, which `undo` reverts once the script
+    /// got past it.
+    fn done(&mut self, undo: Vec<u8>) {
+        self.undo.push(undo);
+        let done = OsString::from(self.undo.len().to_string());
+        push_line(
+            &mut self.script,
+            "echo {} > \"$progress\"",
+            &[done.as_os_str()],
+        );
+    }
+
+    fn stage(&mut self, contents: &[u8]) -> Result<PathBuf> {
+        let staged = env::temp_dir().join(format!("tread-{}-{}", process::id(), self.staged.len()));
+        fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .mode(0o600)
+            .open(&staged)
+            .and_then(|mut file| file.write_all(contents))
+            .map_err(|err| format!("could not write to {}: {err}", staged.display()))?;
+        self.staged.push(staged.clone());
+        Ok(staged)
+    }
+
+    pub fn defer_hook(&mut self, cmd: &str) {
+        self.hooks.push(cmd.to_string());
+    }
+
+    /// Whether privileged operations took effect that can't be undone
+    /// anymore, either because the batch ran or because undoing it failed.
+    pub fn is_committed(&self) -> bool {
+        self.committed
+    }
+
+    pub fn run(&mut self, elevate: &str) -> Result<()> {
+        if !self.script.is_empty() {
+            log!(
+                Info,
+                "Running privileged operations with {}",
+                elevate.split_whitespace().next().unwrap_or_default()
+            );
+
+            // The script runs as another user, so the file recording its
+            // progress is created up front.
+            let progress = state::state_dir()?.join(format!("batch-{}", process::id()));
+            fs::create_dir_all(state::state_dir()?)
+                .and_then(|()| fs::write(&progress, "0"))
+                .map_err(|err| format!("could not write to {}: {err}", progress.display()))?;
+
+            let mut script = Vec::new();
+            push_line(&mut script, "progress={}", &[progress.as_os_str()]);
+            push_line(
+                &mut script,
+                "mkdir -m 700 -- {}",
+                &[saved_dir().as_os_str()],
+            );
+            script.extend_from_slice(&self.script);
+            push_line(&mut script, "rm -rf -- {}", &[saved_dir().as_os_str()]);
+            let status = elevated(elevate, "-e", script);
+
+            for staged in self.staged.drain(..) {
+                fs::remove_file(&staged)
+                    .map_err(|err| format!("could not remove file {}: {err}", staged.display()))?;
+            }
+            let done = fs::read_to_string(&progress)
+                .ok()
+                .and_then(|done| done.trim().parse().ok())
+                .unwrap_or(0);
+            let _ = fs::remove_file(&progress);
+
+            let status = status?;
+            if !status.success() {
+                let err = format!("privileged operations failed: {status}");
+                self.undo(elevate, done)
+                    .map_err(|undo_err| format!("{err}\n    {undo_err}"))?;
+                return Err(err.into());
+            }
+            self.committed = true;
+            self.script.clear();
+            self.undo.clear();
+        }
+
+        for cmd in self.hooks.drain(..) {
+            execute_hook(&cmd)?;
+        }
+        Ok(())
+    }
+
+    /// Undoes the first `done` operations of the script, newest first.
+    fn undo(&mut self, elevate: &str, done: usize) -> Result<()> {
+        if done == 0 {
+            return Ok(());
+        }
+        log!(Warning, "Undoing {done} privileged operations");
+        let mut script = b"failed=0\n".to_vec();
+        for undo in self.undo[..done].iter().rev() {
+            script.extend_from_slice(undo);
+        }
+        push_line(&mut script, "rm -rf -- {}", &[saved_dir().as_os_str()]);
+        script.extend_from_slice(b"exit $failed\n");
+
+        match elevated(elevate, "+e", script) {
+            Ok(status) if status.success() => {
+                log!(Warning, "Undid {done} privileged operations");
+                Ok(())
+            }
+            undone => {
+                self.committed = true;
+                let reason = match undone {
+                    Ok(status) => status.to_string(),
+                    Err(err) => err.to_string(),
+                };
+                Err(format!("could not undo privileged operations: {reason}").into())
+            }
+        }
+    }
+}
+
+/// Runs `script` with `sh` under the elevation command.
+fn elevated(elevate: &str, flag: &str, script: Vec<u8>) -> Result<process::ExitStatus> {
+    let mut elevate_iter = elevate.split_whitespace();
+    let program = elevate_iter
+        .next()
+        .ok_or("could not elevate: No command provided".to_string())?;
+    process::Command::new(program)
+        .args(elevate_iter)
+        .args(["sh", flag, "-c"])
+        .arg(OsString::from_vec(script))
+        .status()
+        .map_err(|err| format!("could not execute {program}: {err}").into())
+}
+
+/// Where the batch of this run keeps the files its operations replace.
+fn saved_dir() -> PathBuf {
+    env::temp_dir().join(format!("tread-{}-saved", process::id()))
+}
+
+/// Appends a line of `sh` code, in which each `{}` stands for the next of
+/// `args`, quoted.
+fn push_line(script: &mut Vec<u8>, code: &str, args: &[&OsStr]) {
+    let mut args = args.iter();
+    for (index, part) in code.split("{}").enumerate() {
+        if index > 0 {
+            if let Some(arg) = args.next() {
+                quote(arg.as_bytes(), script);
+            }
+        }
+        script.extend_from_slice(part.as_bytes());
+    }
+    script.push(b'\n');
+}
+
+/// Like `push_line`, for a line of the undo script, which carries on after
+/// a failure and only reports it at the end.
+fn push_undo(script: &mut Vec<u8>, code: &str, args: &[&OsStr]) {
+    push_line(script, &format!("{code} || failed=1"), args);
+}
+
+/// Single quotes `arg` for `sh`, which takes everything inside literally.
+fn quote(arg: &[u8], script: &mut Vec<u8>) {
+    script.push(b'\'');
+    for byte in arg {
+        if *byte == b'\'' {
+            script.extend_from_slice(b"'\\''");
+        } else {
+            script.push(*byte);
+        }
+    }
+    script.push(b'\'');
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn quotes_arguments_for_sh() {
+        let mut script = Vec::new();
+        quote(b"/etc/greet/it's here.toml", &mut script);
+        assert_eq!(script, b"'/etc/greet/it'\\''s here.toml'");
+
+        let mut script = Vec::new();
+        quote(b"$(rm -rf ~) `id` \"x\"", &mut script);
+        assert_eq!(script, b"'$(rm -rf ~) `id` \"x\"'");
+    }
+
+    #[test]
+    fn fills_in_lines() {
+        let mut script = Vec::new();
+        push_line(
+            &mut script,
+            "mv -- {} {}",
+            &[OsStr::new("/etc/a b"), OsStr::new("/etc/c")],
+        );
+        push_undo(&mut script, "rmdir -- {}", &[OsStr::new("/etc/d")]);
+        assert_eq!(
+            String::from_utf8(script).unwrap(),
+            "mv -- '/etc/a b' '/etc/c'\nrmdir -- '/etc/d' || failed=1\n"
+        );
+    }
+
+    #[test]
+    fn quotes_paths_that_are_not_utf8() {
+        let mut script = Vec::new();
+        push_line(&mut script, "rm -- {}", &[OsStr::from_bytes(b"/etc/\xff'")]);
+        assert_eq!(script, b"rm -- '/etc/\xff'\\'''\n");
+    }
+}
@@ -0,0 +1,255 @@
+use indexmap::IndexMap;
+use serde::{Deserialize, Serialize};
+use std::{env, fs, os::unix::fs::PermissionsExt, path::Path, sync::OnceLock, thread};
+
+/// What is known about the machine tread runs on. Available to templates as
+/// `host`, e.g. `{{ host.hostname }}` or `{{ host.xdg.config_home }}`. Facts
+/// that cannot be determined are empty.
+#[derive(Debug, Serialize)]
+pub struct Facts {
+    pub hostname: String,
+    pub user: String,
+    pub home: String,
+    pub os: &'static str,
+    /// `ID` from os-release, e.g. `arch` or `debian`.
+    pub distro: String,
+    pub arch: &'static str,
+    pub cpus: usize,
+    pub locale: String,
+    pub xdg: Xdg,
+}
+
+/// The XDG base directories, with the defaults of the specification.
+#[derive(Debug, Serialize)]
+pub struct Xdg {
+    pub config_home: String,
+    pub data_home: String,
+    pub state_home: String,
+    pub cache_home: String,
+    pub runtime_dir: String,
+}
+
+/// Gathered on first use and shared by everything after that.
+pub fn facts() -> &'static Facts {
+    static FACTS: OnceLock<Facts> = OnceLock::new();
+    FACTS.get_or_init(|| {
+        let home = env::var("HOME").unwrap_or_default();
+        let xdg_dir = |var: &str, default: &str| {
+            env::var(var)
+                .ok()
+                .filter(|dir| !dir.is_empty())
+                .unwrap_or_else(|| format!("{home}/{default}"))
+        };
+        Facts {
+            hostname: hostname(),
+            user: env::var("USER")
+                .or_else(|_| env::var("LOGNAME"))
+                .unwrap_or_default(),
+            os: env::consts::OS,
+            distro: distro(),
+            arch: env::consts::ARCH,
+            cpus: thread::available_parallelism().map_or(1, usize::from),
+            locale: ["LC_ALL", "LANG"]
+                .iter()
+                .find_map(|var| env::var(var).ok().filter(|locale| !locale.is_empty()))
+                .unwrap_or_default(),
+            xdg: Xdg {
+                config_home: xdg_dir("XDG_CONFIG_HOME", ".config"),
+                data_home: xdg_dir("XDG_DATA_HOME", ".local/share"),
+                state_home: xdg_dir("XDG_STATE_HOME", ".local/state"),
+                cache_home: xdg_dir("XDG_CACHE_HOME", ".cache"),
+                runtime_dir: env::var("XDG_RUNTIME_DIR").unwrap_or_default(),
+            },
+            home,
+        }
+    })
+}
+
+fn hostname() -> String {
+    ["/proc/sys/kernel/hostname", "/etc/hostname"]
+        .iter()
+        .find_map(|file| fs::read_to_string(file).ok())
+        .or_else(|| env::var("HOSTNAME").ok())
+        .map(|hostname| hostname.trim().to_string())
+        .unwrap_or_default()
+}
+
+fn distro() -> String {
+    let Some(os_release) = ["/etc/os-release", "/usr/lib/os-release"]
+        .iter()
+        .find_map(|file| fs::read_to_string(file).ok())
+    else {
+        return String::new();
+    };
+    os_release
+        .lines()
+        .find_map(|line| line.strip_prefix("ID="))
+        .map(|id| id.trim_matches(['"', '\'']).to_string())
+        .unwrap_or_default()
+}
+
+/// Either a single value or a list of them.
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(untagged)]
+pub enum Values {
+    One(String),
+    Many(Vec<String>),
+}
+
+impl Values {
+    fn as_slice(&self) -> &[String] {
+        match self {
+            Values::One(value) => std::slice::from_ref(value),
+            Values::Many(values) => values,
+        }
+    }
+
+    fn describe(&self) -> String {
+        match self.as_slice() {
+            [value] => format!("is {value}"),
+            values => format!("is one of {}", values.join(", ")),
+        }
+    }
+}
+
+/// The `when` clause of an entry. Every condition given has to hold; a list
+/// of hostnames, users, systems or architectures matches any one of them.
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct Condition {
+    hostname: Option<Values>,
+    user: Option<Values>,
+    os: Option<Values>,
+    arch: Option<Values>,
+    /// Environment variables that have to be set.
+    env: Option<Values>,
+    /// Environment variables that have to be set to the given value.
+    env_equals: Option<IndexMap<String, String>>,
+    /// Programs that have to be found in `$PATH`.
+    command: Option<Values>,
+}
+
+impl Condition {
+    pub fn holds(&self) -> bool {
+        let facts = facts();
+        let is_any = |values: &Option<Values>, fact: &str| {
+            values
+                .as_ref()
+                .is_none_or(|values| values.as_slice().iter().any(|value| value == fact))
+        };
+        is_any(&self.hostname, &facts.hostname)
+            && is_any(&self.user, &facts.user)
+            && is_any(&self.os, facts.os)
+            && is_any(&self.arch, facts.arch)
+            && self
+                .env
+                .as_ref()
+                .is_none_or(|vars| vars.as_slice().iter().all(|var| env::var_os(var).is_some()))
+            && self.env_equals.as_ref().is_none_or(|vars| {
+                vars.iter()
+                    .all(|(var, value)| env::var_os(var).is_some_and(|set| set == value.as_str()))
+            })
+            && self.command.as_ref().is_none_or(|programs| {
+                programs
+                    .as_slice()
+                    .iter()
+                    .all(|program| command_exists(program))
+            })
+    }
+
+    /// The conditions in words, e.g. `hostname is laptop and command sway
+    /// exists`.
+    pub fn describe(&self) -> String {
+        let mut conditions = Vec::new();
+        for (fact, values) in [
+            ("hostname", &self.hostname),
+            ("user", &self.user),
+            ("os", &self.os),
+            ("arch", &self.arch),
+        ] {
+            if let Some(values) = values {
+                conditions.push(format!("{fact} {}", values.describe()));
+            }
+        }
+        if let Some(vars) = &self.env {
+            for var in vars.as_slice() {
+                conditions.push(format!("${var} is set"));
+            }
+        }
+        if let Some(vars) = &self.env_equals {
+            for (var, value) in vars {
+                conditions.push(format!("${var} is {value}"));
+            }
+        }
+        if let Some(programs) = &self.command {
+            for program in programs.as_slice() {
+                conditions.push(format!("command {program} exists"));
+            }
+        }
+        if conditions.is_empty() {
+            return "always".to_string();
+        }
+        conditions.join(" and ")
+    }
+}
+
+fn command_exists(program: &str) -> bool {
+    let is_executable = |the_path: &Path| {
+        fs::metadata(the_path)
+            .is_ok_and(|metadata| metadata.is_file() && metadata.permissions().mode() & 0o111 != 0)
+    };
+    if program.contains('/') {
+        return is_executable(Path::new(program));
+    }
+    env::var_os("PATH")
+        .is_some_and(|paths| env::split_paths(&paths).any(|dir| is_executable(&dir.join(program))))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn condition(toml: &str) -> Condition {
+        toml::from_str(toml).unwrap()
+    }
+
+    #[test]
+    fn holds_when_every_condition_does() {
+        let os = env::consts::OS;
+        let arch = env::consts::ARCH;
+        assert!(condition("").holds());
+        assert!(condition(&format!("os = \"{os}\"\narch = [\"none\", \"{arch}\"]")).holds());
+        assert!(!condition(&format!("os = \"{os}\"\narch = \"none\"")).holds());
+        assert!(!condition("os = [\"plan9\", \"none\"]").holds());
+    }
+
+    #[test]
+    fn checks_environment_and_commands() {
+        // Only read, tests run in parallel and share the environment.
+        let path = env::var("PATH").unwrap();
+        assert!(condition("env = \"PATH\"").holds());
+        assert!(!condition("env = [\"PATH\", \"TREAD_TEST_UNSET\"]").holds());
+        assert!(condition(&format!("env_equals = {{ PATH = \"{path}\" }}")).holds());
+        assert!(!condition("env_equals = { PATH = \"\" }").holds());
+        assert!(condition("command = \"sh\"").holds());
+        assert!(condition("command = \"/bin/sh\"").holds());
+        assert!(!condition("command = \"tread-test-missing\"").holds());
+    }
+
+    #[test]
+    fn describes_conditions() {
+        assert_eq!(condition("").describe(), "always");
+        assert_eq!(
+            condition(
+                "hostname = [\"laptop\", \"desk\"]\nenv = \"WAYLAND_DISPLAY\"\ncommand = \"sway\""
+            )
+            .describe(),
+            "hostname is one of laptop, desk and $WAYLAND_DISPLAY is set and command sway exists"
+        );
+    }
+
+    #[test]
+    fn rejects_unknown_conditions() {
+        assert!(toml::from_str::<Condition>("kernel = \"linux\"").is_err());
+    }
+}
@@ -1,5 +1,12 @@
+use std::{
+    cell::RefCell,
+    io::{self, Write},
+    sync::atomic::AtomicBool,
+};
+
 pub type Result<T> = std::result::Result<T, Error>;
 
+#[derive(Debug)]
 pub struct Error {
     ctx: String,
 }
@@ -32,6 +39,71 @@ impl From<flagge::Error> for Error {
     }
 }
 
+/// Silences info and warning logs, e.g. while a sync is being planned.
+/// Errors are always printed.
+pub static QUIET: AtomicBool = AtomicBool::new(false);
+
+#[derive(Clone, Copy)]
+enum Stream {
+    Out,
+    Err,
+}
+
+/// Writes to stdout and stderr, in the order they were made.
+type Output = Vec<(Stream, Vec<u8>)>;
+
+thread_local! {
+    /// Output of the entry this thread is working on, held back so that the
+    /// logs of entries processed concurrently do not interleave.
+    static BUFFER: RefCell<Option<Output>> = const { RefCell::new(None) };
+}
+
+/// Holds back everything this thread prints through `print_out` and
+/// `print_err` until `flush_out` is called.
+pub fn buffer_out() {
+    BUFFER.with_borrow_mut(|buffer| *buffer = Some(Vec::new()));
+}
+
+/// Prints the held back output in one piece, in the order it was written,
+/// and stops holding it back.
+pub fn flush_out() {
+    if let Some(buffer) = BUFFER.with_borrow_mut(Option::take) {
+        let mut stdout = io::stdout().lock();
+        let mut stderr = io::stderr().lock();
+        for (stream, bytes) in buffer {
+            let _ = match stream {
+                Stream::Out => stdout.write_all(&bytes).and_then(|()| stdout.flush()),
+                Stream::Err => stderr.write_all(&bytes),
+            };
+        }
+    }
+}
+
+pub fn print_out(bytes: &[u8]) -> io::Result<()> {
+    print_to(Stream::Out, bytes)
+}
+
+pub fn print_err(bytes: &[u8]) -> io::Result<()> {
+    print_to(Stream::Err, bytes)
+}
+
+fn print_to(stream: Stream, bytes: &[u8]) -> io::Result<()> {
+    let buffered = BUFFER.with_borrow_mut(|buffer| match buffer {
+        Some(buffer) => {
+            buffer.push((stream, bytes.to_vec()));
+            true
+        }
+        None => false,
+    });
+    if buffered {
+        return Ok(());
+    }
+    match stream {
+        Stream::Out => io::stdout().write_all(bytes),
+        Stream::Err => io::stderr().write_all(bytes),
+    }
+}
+
 pub enum LogLevel {
     Info,
     Warning,
@@ -41,17 +113,19 @@ pub enum LogLevel {
 macro_rules! log {
     ($loglevel:ident, $($arg:tt)*) => {
         match LogLevel::$loglevel {
+            LogLevel::Info if $crate::helper::QUIET.load(std::sync::atomic::Ordering::Relaxed) => {}
+            LogLevel::Warning if $crate::helper::QUIET.load(std::sync::atomic::Ordering::Relaxed) => {}
             LogLevel::Info => {
-                print!("\x1b[0;32mINFO\x1b[0m: ");
-                println!($($arg)*);
+                let line = format!("\x1b[0;32mINFO\x1b[0m: {}\n", format_args!($($arg)*));
+                let _ = $crate::helper::print_out(line.as_bytes());
             }
             LogLevel::Warning => {
-                print!("\x1b[0;33mWARNING\x1b[0m: ");
-                println!($($arg)*);
+                let line = format!("\x1b[0;33mWARNING\x1b[0m: {}\n", format_args!($($arg)*));
+                let _ = $crate::helper::print_out(line.as_bytes());
             }
             LogLevel::Error => {
-                eprint!("\x1b[0;31mERROR\x1b[0m: ");
-                eprintln!($($arg)*);
+                let line = format!("\x1b[0;31mERROR\x1b[0m: {}\n", format_args!($($arg)*));
+                let _ = $crate::helper::print_err(line.as_bytes());
             }
         }
     };
@@ -0,0 +1,201 @@
+use crate::Result;
+
+use glob::{MatchOptions, Pattern};
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+pub const IGNORE_FILE: &str = ".treadignore";
+
+const MATCH_OPTIONS: MatchOptions = MatchOptions {
+    case_sensitive: true,
+    require_literal_separator: true,
+    require_literal_leading_dot: false,
+};
+
+/// Gitignore-style rules deciding which files of a recursive entry are left
+/// out. Later rules take precedence over earlier ones, so rules read from a
+/// nested `.treadignore` override those of its parent directories.
+#[derive(Debug, Clone, Default)]
+pub struct IgnoreRules {
+    rules: Vec<Rule>,
+}
+
+#[derive(Debug, Clone)]
+struct Rule {
+    base: PathBuf,
+    pattern: Pattern,
+    negated: bool,
+    dir_only: bool,
+    anchored: bool,
+}
+
+impl IgnoreRules {
+    /// Rules given in the manifest, relative to the entry's target.
+    pub fn new(base: &Path, patterns: &[String]) -> Result<Self> {
+        let mut rules = Self::default();
+        for pattern in patterns {
+            rules
+                .add(base, pattern)
+                .map_err(|err| format!("invalid ignore pattern {pattern}: {err}"))?;
+        }
+        Ok(rules)
+    }
+
+    /// Rules in effect inside `dir`, including the ones from its own
+    /// `.treadignore`.
+    pub fn descend(&self, dir: &Path) -> Result<Self> {
+        let ignore_file = dir.join(IGNORE_FILE);
+        if !ignore_file.is_file() {
+            return Ok(self.clone());
+        }
+
+        let data = fs::read_to_string(&ignore_file)
+            .map_err(|err| format!("could not read file {}: {err}", ignore_file.display()))?;
+        let mut rules = self.clone();
+        for (line_number, line) in data.lines().enumerate() {
+            let line = line.trim_end();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            rules.add(dir, line).map_err(|err| {
+                format!(
+                    "invalid pattern in {}:{}: {err}",
+                    ignore_file.display(),
+                    line_number + 1
+                )
+            })?;
+        }
+        Ok(rules)
+    }
+
+    pub fn is_ignored(&self, the_path: &Path, is_dir: bool) -> bool {
+        if the_path.file_name().is_some_and(|name| name == IGNORE_FILE) {
+            return true;
+        }
+
+        let mut ignored = false;
+        for rule in &self.rules {
+            if rule.dir_only && !is_dir {
+                continue;
+            }
+            let Ok(relative) = the_path.strip_prefix(&rule.base) else {
+                continue;
+            };
+            let matched = if rule.anchored {
+                rule.pattern.matches_path_with(relative, MATCH_OPTIONS)
+            } else {
+                relative.file_name().is_some_and(|name| {
+                    rule.pattern
+                        .matches_with(&name.to_string_lossy(), MATCH_OPTIONS)
+                })
+            };
+            if matched {
+                ignored = !rule.negated;
+            }
+        }
+        ignored
+    }
+
+    fn add(&mut self, base: &Path, pattern: &str) -> std::result::Result<(), glob::PatternError> {
+        let (negated, pattern) = match pattern.strip_prefix('!') {
+            Some(pattern) => (true, pattern),
+            None => (false, pattern.strip_prefix('\\').unwrap_or(pattern)),
+        };
+        let (dir_only, pattern) = match pattern.strip_suffix('/') {
+            Some(pattern) => (true, pattern),
+            None => (false, pattern),
+        };
+        // As in gitignore, a pattern containing a slash is matched against the
+        // path relative to its base, otherwise against the file name only.
+        let anchored = pattern.contains('/');
+        let pattern = pattern.strip_prefix('/').unwrap_or(pattern);
+
+        self.rules.push(Rule {
+            base: base.to_path_buf(),
+            pattern: Pattern::new(pattern)?,
+            negated,
+            dir_only,
+            anchored,
+        });
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::{env, process};
+
+    fn rules(patterns: &[&str]) -> IgnoreRules {
+        let patterns: Vec<String> = patterns.iter().map(|pattern| pattern.to_string()).collect();
+        IgnoreRules::new(Path::new("/dots/nvim"), &patterns).unwrap()
+    }
+
+    #[test]
+    fn matches_file_names_anywhere() {
+        let rules = rules(&["*.swp"]);
+        assert!(rules.is_ignored(Path::new("/dots/nvim/init.swp"), false));
+        assert!(rules.is_ignored(Path::new("/dots/nvim/lua/a.swp"), false));
+        assert!(!rules.is_ignored(Path::new("/dots/nvim/init.lua"), false));
+        assert!(!rules.is_ignored(Path::new("/dots/other/init.swp"), false));
+    }
+
+    #[test]
+    fn anchors_patterns_containing_a_slash() {
+        let rules = rules(&["/lua/*.lua", "doc/tags"]);
+        assert!(rules.is_ignored(Path::new("/dots/nvim/lua/init.lua"), false));
+        assert!(!rules.is_ignored(Path::new("/dots/nvim/lua/plugins/a.lua"), false));
+        assert!(!rules.is_ignored(Path::new("/dots/nvim/init.lua"), false));
+        assert!(rules.is_ignored(Path::new("/dots/nvim/doc/tags"), false));
+        assert!(!rules.is_ignored(Path::new("/dots/nvim/plugin/doc/tags"), false));
+    }
+
+    #[test]
+    fn matches_directories_only_with_a_trailing_slash() {
+        let rules = rules(&[".git/"]);
+        assert!(rules.is_ignored(Path::new("/dots/nvim/.git"), true));
+        assert!(!rules.is_ignored(Path::new("/dots/nvim/.git"), false));
+    }
+
+    #[test]
+    fn later_rules_take_precedence() {
+        let rules = rules(&["*.lua", "!init.lua", "\\!bang"]);
+        assert!(rules.is_ignored(Path::new("/dots/nvim/keys.lua"), false));
+        assert!(!rules.is_ignored(Path::new("/dots/nvim/init.lua"), false));
+        assert!(rules.is_ignored(Path::new("/dots/nvim/!bang"), false));
+    }
+
+    #[test]
+    fn always_ignores_the_ignore_file() {
+        let rules = rules(&[]);
+        assert!(rules.is_ignored(Path::new("/dots/nvim/.treadignore"), false));
+        assert!(!rules.is_ignored(Path::new("/dots/nvim/init.lua"), false));
+    }
+
+    #[test]
+    fn reads_nested_ignore_files() {
+        let dir = env::temp_dir().join(format!("tread-ignore-{}", process::id()));
+        fs::create_dir_all(dir.join("lua")).unwrap();
+        fs::write(
+            dir.join("lua").join(IGNORE_FILE),
+            "# comment\n\n!keep.swp\nlocal/\n",
+        )
+        .unwrap();
+
+        let rules = IgnoreRules::new(&dir, &["*.swp".to_string()])
+            .unwrap()
+            .descend(&dir)
+            .unwrap();
+        let nested = rules.descend(&dir.join("lua")).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert!(rules.is_ignored(&dir.join("lua/keep.swp"), false));
+        assert!(!nested.is_ignored(&dir.join("lua/keep.swp"), false));
+        assert!(nested.is_ignored(&dir.join("lua/other.swp"), false));
+        assert!(nested.is_ignored(&dir.join("lua/local"), true));
+        assert!(!nested.is_ignored(&dir.join("local"), true));
+    }
+}
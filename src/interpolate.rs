@@ -0,0 +1,208 @@
+use crate::Result;
+
+use indexmap::IndexMap;
+use std::env;
+
+/// What a `${...}` refers to.
+enum Reference<'a> {
+    /// `${name}`, a variable of the manifest or else of the environment.
+    Any(&'a str),
+    /// `${var:name}`, only a variable of the manifest.
+    Var(&'a str),
+    /// `${env:NAME}`, only a variable of the environment.
+    Env(&'a str),
+}
+
+/// Expands every `${...}` in `input` through `lookup`. `$${` stands for a
+/// literal `${`.
+fn expand_with(input: &str, lookup: &mut dyn FnMut(Reference) -> Result<String>) -> Result<String> {
+    let mut expanded = String::with_capacity(input.len());
+    let mut rest = input;
+    while let Some(pos) = rest.find('$') {
+        expanded.push_str(&rest[..pos]);
+        rest = &rest[pos..];
+        if let Some(stripped) = rest.strip_prefix("$${") {
+            expanded.push_str("${");
+            rest = stripped;
+        } else if let Some(stripped) = rest.strip_prefix("${") {
+            let end = stripped
+                .find('}')
+                .ok_or(format!("missing closing }} in {input}"))?;
+            let reference = match &stripped[..end] {
+                "" | "var:" | "env:" => {
+                    return Err(format!("missing variable name in {input}").into())
+                }
+                name => {
+                    if let Some(name) = name.strip_prefix("var:") {
+                        Reference::Var(name)
+                    } else if let Some(name) = name.strip_prefix("env:") {
+                        Reference::Env(name)
+                    } else {
+                        Reference::Any(name)
+                    }
+                }
+            };
+            expanded.push_str(&lookup(reference)?);
+            rest = &stripped[end + 1..];
+        } else {
+            expanded.push('$');
+            rest = &rest[1..];
+        }
+    }
+    expanded.push_str(rest);
+    Ok(expanded)
+}
+
+fn env_var(name: &str) -> Result<String> {
+    env::var(name).map_err(|_| format!("undefined environment variable {name}").into())
+}
+
+/// Expands `input` with variables that were already resolved.
+pub fn expand(input: &str, variables: &IndexMap<String, String>) -> Result<String> {
+    expand_with(input, &mut |reference| match reference {
+        Reference::Var(name) => variables
+            .get(name)
+            .cloned()
+            .ok_or(format!("undefined variable {name}").into()),
+        Reference::Env(name) => env_var(name),
+        Reference::Any(name) => match variables.get(name) {
+            Some(value) => Ok(value.clone()),
+            None => env_var(name).map_err(|_| format!("undefined variable {name}").into()),
+        },
+    })
+}
+
+/// Expands the variables of the manifest, which may refer to each other.
+pub fn resolve_variables(variables: &IndexMap<String, String>) -> Result<IndexMap<String, String>> {
+    let mut resolved = IndexMap::with_capacity(variables.len());
+    for name in variables.keys() {
+        resolve_variable(name, variables, &mut resolved, &mut Vec::new())?;
+    }
+    // Back in the order of the manifest, dependencies were resolved first.
+    Ok(variables
+        .keys()
+        .map(|name| (name.clone(), resolved[name].clone()))
+        .collect())
+}
+
+fn resolve_variable(
+    name: &str,
+    variables: &IndexMap<String, String>,
+    resolved: &mut IndexMap<String, String>,
+    visiting: &mut Vec<String>,
+) -> Result<String> {
+    if let Some(value) = resolved.get(name) {
+        return Ok(value.clone());
+    }
+    if let Some(pos) = visiting.iter().position(|seen| seen == name) {
+        let cycle = visiting[pos..].join(" -> ");
+        return Err(format!("variable cycle: {cycle} -> {name}").into());
+    }
+
+    visiting.push(name.to_string());
+    // Errors of the variables referred to already name the culprit.
+    let mut nested = false;
+    let value = expand_with(&variables[name], &mut |reference| match reference {
+        Reference::Var(other) | Reference::Any(other) if variables.contains_key(other) => {
+            resolve_variable(other, variables, resolved, visiting).inspect_err(|_| nested = true)
+        }
+        Reference::Var(other) => Err(format!("undefined variable {other}").into()),
+        Reference::Env(other) => env_var(other),
+        Reference::Any(other) => {
+            env_var(other).map_err(|_| format!("undefined variable {other}").into())
+        }
+    })
+    .map_err(|err| match nested {
+        true => err,
+        false => format!("invalid variable {name}: {err}").into(),
+    })?;
+    visiting.pop();
+
+    resolved.insert(name.to_string(), value.clone());
+    Ok(value)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn variables(pairs: &[(&str, &str)]) -> IndexMap<String, String> {
+        pairs
+            .iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn expands_variables() {
+        let variables = variables(&[("dots", "/home/me/dots"), ("HOME", "/shadowed")]);
+        assert_eq!(
+            expand("${dots}/nvim", &variables).unwrap(),
+            "/home/me/dots/nvim"
+        );
+        assert_eq!(
+            expand("${var:dots}/${dots}", &variables).unwrap(),
+            "/home/me/dots//home/me/dots"
+        );
+        assert_eq!(expand("${HOME}", &variables).unwrap(), "/shadowed");
+    }
+
+    #[test]
+    fn falls_back_to_the_environment() {
+        // Only read, tests run in parallel and share the environment.
+        let path = env::var("PATH").unwrap();
+        let variables = variables(&[("PATH", "from manifest")]);
+        assert_eq!(expand("${env:PATH}", &variables).unwrap(), path);
+        assert_eq!(expand("${PATH}", &IndexMap::new()).unwrap(), path);
+        assert!(expand("${var:PATH}", &IndexMap::new()).is_err());
+    }
+
+    #[test]
+    fn escapes_and_lone_dollars() {
+        let variables = variables(&[("name", "value")]);
+        assert_eq!(expand("$${name}", &variables).unwrap(), "${name}");
+        assert_eq!(expand("$$${name}", &variables).unwrap(), "$${name}");
+        assert_eq!(
+            expand("cost: 5$ $name", &variables).unwrap(),
+            "cost: 5$ $name"
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_references() {
+        let variables = variables(&[("name", "value")]);
+        for input in [
+            "${name",
+            "${}",
+            "${var:}",
+            "${env:}",
+            "${tread_test_undefined}",
+        ] {
+            assert!(expand(input, &variables).is_err(), "{input}");
+        }
+    }
+
+    #[test]
+    fn resolves_variables_referring_to_each_other() {
+        let resolved = resolve_variables(&variables(&[
+            ("config", "${home}/.config"),
+            ("home", "/home/me"),
+            ("nvim", "${var:config}/nvim"),
+        ]))
+        .unwrap();
+        assert_eq!(
+            resolved.into_iter().collect::<Vec<_>>(),
+            [
+                ("config".to_string(), "/home/me/.config".to_string()),
+                ("home".to_string(), "/home/me".to_string()),
+                ("nvim".to_string(), "/home/me/.config/nvim".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn reports_variable_cycles() {
+        let err = resolve_variables(&variables(&[("a", "${b}"), ("b", "x${a}")])).unwrap_err();
+        assert_eq!(err.to_string(), "variable cycle: a -> b -> a");
+    }
+}
@@ -0,0 +1,297 @@
+use crate::{backup, helper::LogLevel, Result};
+
+use std::{
+    fs,
+    os::unix::fs::{symlink as symlink_unix, PermissionsExt},
+    path::{Path, PathBuf},
+    process,
+};
+
+/// How to undo each change made during a sync, so that a failed run can put
+/// everything back the way it was. Replaced files are kept under
+/// `$XDG_STATE_HOME/tread/journal` until the run is over. Operations of
+/// privileged entries are carried out and undone by the batch.
+pub struct Journal {
+    /// Where the journal directory is created, usually `state::state_dir()`.
+    state_dir: PathBuf,
+    saved_dir: Option<PathBuf>,
+    changes: Vec<Change>,
+}
+
+enum Change {
+    /// A path that did not exist before.
+    Created(PathBuf),
+    /// A file or directory that was overwritten or removed, with a copy of
+    /// its contents.
+    Replaced {
+        path: PathBuf,
+        saved: PathBuf,
+    },
+    /// A symlink that was replaced or removed.
+    Unlinked {
+        path: PathBuf,
+        original: PathBuf,
+    },
+    /// A directory that was removed.
+    RemovedDir(PathBuf),
+    Permissions {
+        path: PathBuf,
+        mode: u32,
+    },
+    /// A path that was moved, e.g. into a backup.
+    Moved {
+        from: PathBuf,
+        to: PathBuf,
+    },
+    /// A hook that ran, which there is no undoing.
+    Hook(String),
+}
+
+impl Journal {
+    pub fn new(state_dir: PathBuf) -> Self {
+        Self {
+            state_dir,
+            saved_dir: None,
+            changes: Vec::new(),
+        }
+    }
+
+    pub fn created(&mut self, the_path: &Path) {
+        self.changes.push(Change::Created(the_path.to_path_buf()));
+    }
+
+    /// To be called right before `the_path` is overwritten or removed.
+    pub fn replacing(&mut self, the_path: &Path) -> Result<()> {
+        let metadata = match fs::symlink_metadata(the_path) {
+            Ok(metadata) => metadata,
+            Err(_) => {
+                self.created(the_path);
+                return Ok(());
+            }
+        };
+        if metadata.is_symlink() {
+            let original = fs::read_link(the_path)
+                .map_err(|err| format!("could not read symlink {}: {err}", the_path.display()))?;
+            self.changes.push(Change::Unlinked {
+                path: the_path.to_path_buf(),
+                original,
+            });
+        } else {
+            let saved = self.saved_dir()?.join(self.changes.len().to_string());
+            backup::copy_all(the_path, &saved)?;
+            self.changes.push(Change::Replaced {
+                path: the_path.to_path_buf(),
+                saved,
+            });
+        }
+        Ok(())
+    }
+
+    pub fn removed_dir(&mut self, the_path: &Path) {
+        self.changes
+            .push(Change::RemovedDir(the_path.to_path_buf()));
+    }
+
+    pub fn permissions(&mut self, the_path: &Path) {
+        // Whatever was created during the run is removed on rollback anyway.
+        let created = self
+            .changes
+            .iter()
+            .any(|change| matches!(change, Change::Created(path) if path == the_path));
+        if created {
+            return;
+        }
+        if let Ok(metadata) = fs::metadata(the_path) {
+            self.changes.push(Change::Permissions {
+                path: the_path.to_path_buf(),
+                mode: metadata.permissions().mode() & 0o7777,
+            });
+        }
+    }
+
+    pub fn moved(&mut self, from: &Path, to: &Path) {
+        self.changes.push(Change::Moved {
+            from: from.to_path_buf(),
+            to: to.to_path_buf(),
+        });
+    }
+
+    pub fn ran_hook(&mut self, cmd: &str) {
+        self.changes.push(Change::Hook(cmd.to_string()));
+    }
+
+    /// Undoes every change, newest first. Failures are logged and do not
+    /// stop the remaining changes from being reverted.
+    pub fn rollback(mut self) {
+        if self.changes.is_empty() {
+            return;
+        }
+        log!(Warning, "Rolling back {} changes", self.changes.len());
+        while let Some(change) = self.changes.pop() {
+            let (the_path, reverted) = match &change {
+                Change::Created(the_path) => (the_path, remove(the_path)),
+                Change::Replaced { path, saved } => (
+                    path,
+                    remove(path).and_then(|()| backup::move_path(saved, path)),
+                ),
+                Change::Unlinked { path, original } => (
+                    path,
+                    remove(path).and_then(|()| {
+                        symlink_unix(original, path).map_err(|err| {
+                            format!("could not symlink {}: {err}", path.display()).into()
+                        })
+                    }),
+                ),
+                Change::RemovedDir(the_path) => (
+                    the_path,
+                    fs::create_dir(the_path).map_err(|err| {
+                        format!("could not create dir {}: {err}", the_path.display()).into()
+                    }),
+                ),
+                Change::Permissions { path, mode } => (
+                    path,
+                    fs::set_permissions(path, fs::Permissions::from_mode(*mode)).map_err(|err| {
+                        format!("could not set permissions of {}: {err}", path.display()).into()
+                    }),
+                ),
+                Change::Moved { from, to } => (from, backup::move_path(to, from)),
+                Change::Hook(cmd) => {
+                    log!(Warning, "Hook {cmd} already ran and is not undone");
+                    continue;
+                }
+            };
+            match reverted {
+                Ok(()) => log!(Warning, "Reverted {}", the_path.display()),
+                Err(err) => log!(Error, "could not revert {}: {err}", the_path.display()),
+            }
+        }
+        self.discard();
+    }
+
+    /// Drops the copies of replaced files once they are no longer needed.
+    pub fn discard(self) {
+        if let Some(saved_dir) = self.saved_dir {
+            if let Err(err) = fs::remove_dir_all(&saved_dir) {
+                log!(Warning, "Could not remove {}: {err}", saved_dir.display());
+            }
+            // Only succeeds once no other run is journaling.
+            if let Some(parent) = saved_dir.parent() {
+                let _ = fs::remove_dir(parent);
+            }
+        }
+    }
+
+    fn saved_dir(&mut self) -> Result<PathBuf> {
+        if let Some(saved_dir) = &self.saved_dir {
+            return Ok(saved_dir.clone());
+        }
+        let saved_dir = self
+            .state_dir
+            .join("journal")
+            .join(process::id().to_string());
+        fs::create_dir_all(&saved_dir)
+            .map_err(|err| format!("could not create dir {}: {err}", saved_dir.display()))?;
+        self.saved_dir = Some(saved_dir.clone());
+        Ok(saved_dir)
+    }
+}
+
+/// Removes whatever is at `the_path`, leaving non-empty directories alone.
+fn remove(the_path: &Path) -> Result<()> {
+    let Ok(metadata) = fs::symlink_metadata(the_path) else {
+        return Ok(());
+    };
+    if metadata.is_dir() {
+        fs::remove_dir(the_path)
+            .map_err(|err| format!("could not remove dir {}: {err}", the_path.display()).into())
+    } else {
+        fs::remove_file(the_path)
+            .map_err(|err| format!("could not remove file {}: {err}", the_path.display()).into())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{write_atomic, write_destination};
+
+    use std::env;
+
+    #[test]
+    fn rollback_reverts_every_change() {
+        let dir = env::temp_dir().join(format!("tread-journal-{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let mut journal = Journal::new(dir.join("state"));
+
+        let created = dir.join("created");
+        journal.created(&created);
+        fs::create_dir(&created).unwrap();
+        journal.created(&created.join("file"));
+        fs::write(created.join("file"), "new").unwrap();
+
+        let replaced = dir.join("replaced");
+        fs::write(&replaced, "old").unwrap();
+        journal.replacing(&replaced).unwrap();
+        fs::write(&replaced, "new").unwrap();
+
+        let unlinked = dir.join("unlinked");
+        symlink_unix("old", &unlinked).unwrap();
+        journal.replacing(&unlinked).unwrap();
+        fs::remove_file(&unlinked).unwrap();
+        symlink_unix("new", &unlinked).unwrap();
+
+        let removed_dir = dir.join("removed_dir");
+        fs::create_dir(&removed_dir).unwrap();
+        fs::remove_dir(&removed_dir).unwrap();
+        journal.removed_dir(&removed_dir);
+
+        let chmoded = dir.join("chmoded");
+        fs::write(&chmoded, "").unwrap();
+        fs::set_permissions(&chmoded, fs::Permissions::from_mode(0o640)).unwrap();
+        journal.permissions(&chmoded);
+        fs::set_permissions(&chmoded, fs::Permissions::from_mode(0o600)).unwrap();
+
+        let moved = dir.join("moved");
+        let backup = dir.join("backup");
+        fs::write(&moved, "moved").unwrap();
+        fs::rename(&moved, &backup).unwrap();
+        journal.moved(&moved, &backup);
+
+        journal.ran_hook("true");
+        journal.rollback();
+
+        assert!(!created.exists());
+        assert_eq!(fs::read_to_string(&replaced).unwrap(), "old");
+        assert_eq!(fs::read_link(&unlinked).unwrap(), Path::new("old"));
+        assert!(removed_dir.is_dir());
+        assert_eq!(
+            fs::metadata(&chmoded).unwrap().permissions().mode() & 0o7777,
+            0o640
+        );
+        assert_eq!(fs::read_to_string(&moved).unwrap(), "moved");
+        assert!(!backup.exists());
+        // The copies of replaced files are gone along with the journal.
+        assert!(!dir.join("state/tread/journal").exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn rollback_restores_files_written_through_symlinks() {
+        let dir = env::temp_dir().join(format!("tread-journal-symlink-{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let mut journal = Journal::new(dir.join("state"));
+
+        let file = dir.join("file");
+        let link = dir.join("link");
+        fs::write(&file, "old").unwrap();
+        symlink_unix(&file, &link).unwrap();
+        journal.replacing(&write_destination(&link)).unwrap();
+        write_atomic(&link, b"new", None).unwrap();
+        assert_eq!(fs::read_to_string(&file).unwrap(), "new");
+
+        journal.rollback();
+        assert_eq!(fs::read_to_string(&file).unwrap(), "old");
+        assert_eq!(fs::read_link(&link).unwrap(), file);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
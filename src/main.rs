@@ -1,33 +1,91 @@
 mod cli;
 mod colors;
+mod diff;
+mod facts;
+mod ignore;
+mod interpolate;
+mod schedule;
+mod state;
+mod status;
 
-use serde::Deserialize;
+use indexmap::IndexMap;
+use serde::{Deserialize, Serialize};
 use std::{
-    collections::HashMap,
-    env, fs,
+    collections::{HashMap, HashSet},
+    env, ffi, fs,
     io::{self, Write},
-    os::unix::fs::symlink as symlink_unix,
+    mem,
+    os::unix::{
+        ffi::OsStrExt,
+        fs::{fchown, symlink as symlink_unix, MetadataExt, OpenOptionsExt, PermissionsExt},
+    },
     path, process,
+    sync::{atomic::Ordering, Mutex, MutexGuard, PoisonError},
+    thread,
 };
 
 #[macro_use]
 mod helper;
 use helper::*;
 
-#[derive(Debug, Deserialize)]
+mod backup;
+mod elevate;
+mod journal;
+
+#[derive(Debug, Deserialize, Serialize)]
 struct Manifest {
+    #[serde(skip)]
+    path: path::PathBuf,
     options: ManifestOpt,
-    variables: Option<HashMap<String, String>>,
-    entries: HashMap<String, Vec<Entry>>,
+    variables: Option<IndexMap<String, String>>,
+    entries: IndexMap<String, Vec<Entry>>,
+    #[serde(skip_serializing)]
+    include: Option<Vec<String>>,
+    /// The file each entry was defined in.
+    #[serde(skip)]
+    sources: HashMap<String, path::PathBuf>,
+    /// The file each variable was defined in.
+    #[serde(skip)]
+    variable_sources: HashMap<String, path::PathBuf>,
+    #[serde(default, skip_serializing)]
+    hosts: IndexMap<String, Profile>,
+    #[serde(default, skip_serializing)]
+    profiles: IndexMap<String, Profile>,
+}
+
+/// A file pulled in by `include`. Relative paths in its entries are relative
+/// to the fragment.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Fragment {
+    include: Option<Vec<String>>,
+    variables: Option<IndexMap<String, String>>,
+    #[serde(default)]
+    entries: IndexMap<String, Vec<Entry>>,
 }
 
+/// Overrides for a single host or profile.
 #[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Profile {
+    options: Option<toml::Table>,
+    variables: Option<IndexMap<String, String>>,
+    entries: Option<IndexMap<String, Vec<Entry>>>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
 struct ManifestOpt {
     wallpaper: Option<path::PathBuf>,
     #[serde(default = "default_theme_option")]
     theme: String,
     #[serde(default = "default_variant_option")]
     variant: String,
+    #[serde(default = "default_backup_option")]
+    backup: bool,
+    #[serde(default = "default_relative_option")]
+    relative: bool,
+    #[serde(default = "default_elevate_option")]
+    elevate: String,
 }
 
 fn default_theme_option() -> String {
@@ -38,22 +96,456 @@ fn default_variant_option() -> String {
     "tonal_spot".to_string()
 }
 
-#[derive(Debug, Deserialize)]
+const fn default_backup_option() -> bool {
+    false
+}
+
+const fn default_relative_option() -> bool {
+    false
+}
+
+fn default_elevate_option() -> String {
+    "sudo".to_string()
+}
+
+#[derive(Debug, Deserialize, Serialize)]
 struct Entry {
     target: Option<path::PathBuf>,
     dest: path::PathBuf,
     template: Option<path::PathBuf>,
     #[serde(default = "default_recursive_option")]
     recursive: bool,
+    #[serde(default = "default_fold_option")]
+    fold: bool,
+    ignore: Option<Vec<String>>,
+    #[serde(default = "default_mode_option")]
+    mode: InstallMode,
+    relative: Option<bool>,
+    permissions: Option<String>,
+    dir_permissions: Option<String>,
+    #[serde(default = "default_privileged_option")]
+    privileged: bool,
     pre_hooks: Option<Vec<String>>,
     post_hooks: Option<Vec<String>>,
+    depends_on: Option<Vec<String>>,
+    when: Option<facts::Condition>,
+    /// The manifest or fragment the entry was defined in.
+    #[serde(skip)]
+    origin: path::PathBuf,
 }
 
 const fn default_recursive_option() -> bool {
     false
 }
 
-type TemplateContext = HashMap<String, String>;
+const fn default_fold_option() -> bool {
+    false
+}
+
+const fn default_privileged_option() -> bool {
+    false
+}
+
+impl Entry {
+    fn interpolate(&mut self, variables: &IndexMap<String, String>) -> Result<()> {
+        let expand_path = |field: &str, the_path: &mut path::PathBuf| -> Result<()> {
+            // Paths are read from TOML strings, so they are always UTF-8.
+            if let Some(raw) = the_path.to_str() {
+                *the_path = interpolate::expand(raw, variables)
+                    .map_err(|err| format!("invalid {field}: {err}"))?
+                    .into();
+            }
+            Ok(())
+        };
+        if let Some(target) = &mut self.target {
+            expand_path("target", target)?;
+        }
+        expand_path("dest", &mut self.dest)?;
+        if let Some(template) = &mut self.template {
+            expand_path("template", template)?;
+        }
+        for cmd in self
+            .pre_hooks
+            .iter_mut()
+            .chain(self.post_hooks.iter_mut())
+            .flatten()
+        {
+            *cmd = interpolate::expand(cmd, variables)
+                .map_err(|err| format!("invalid hook {cmd}: {err}"))?;
+        }
+        Ok(())
+    }
+
+    /// Makes relative paths of an entry from a fragment relative to the
+    /// fragment rather than the main manifest.
+    fn rebase(&mut self) -> Result<()> {
+        let dir = self.origin.parent().ok_or(format!(
+            "could not access parent dir of {}",
+            self.origin.display()
+        ))?;
+        for the_path in [
+            self.target.as_mut(),
+            Some(&mut self.dest),
+            self.template.as_mut(),
+        ]
+        .into_iter()
+        .flatten()
+        {
+            if the_path.is_relative() && !the_path.starts_with("~") {
+                *the_path = dir.join(&the_path);
+            }
+        }
+        Ok(())
+    }
+
+    /// The entry `name` along with the file it was defined in, for errors.
+    fn label(&self, name: &str) -> String {
+        format!("{name} in {}", self.origin.display())
+    }
+
+    /// Settings that would silently have no effect are rejected.
+    fn validate(&self) -> Result<()> {
+        if self.permissions.is_some() && self.template.is_none() && self.mode != InstallMode::Copy {
+            return Err(
+                "permissions only apply to copies and templates, links share the mode of their target"
+                    .to_string()
+                    .into(),
+            );
+        }
+        Ok(())
+    }
+
+    /// Whether the `when` clause, if any, holds on this machine.
+    fn applies(&self) -> bool {
+        self.when.as_ref().is_none_or(facts::Condition::holds)
+    }
+
+    /// Whether a directory target is walked rather than installed as a whole.
+    fn walks_dirs(&self) -> bool {
+        self.recursive || self.fold || self.mode != InstallMode::Symlink
+    }
+
+    /// Permission bits of copied and generated files.
+    fn permissions(&self) -> Result<Option<u32>> {
+        parse_permissions(self.permissions.as_deref())
+    }
+
+    /// Permission bits of the directories created for the entry.
+    fn dir_permissions(&self) -> Result<Option<u32>> {
+        parse_permissions(self.dir_permissions.as_deref())
+    }
+}
+
+fn parse_permissions(mode: Option<&str>) -> Result<Option<u32>> {
+    let Some(mode) = mode else {
+        return Ok(None);
+    };
+    match u32::from_str_radix(mode, 8) {
+        Ok(bits) if bits <= 0o7777 => Ok(Some(bits)),
+        _ => Err(
+            format!("invalid permissions {mode}, expected an octal mode such as \"0644\"").into(),
+        ),
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+enum InstallMode {
+    Symlink,
+    Copy,
+    Hardlink,
+}
+
+const fn default_mode_option() -> InstallMode {
+    InstallMode::Symlink
+}
+
+#[derive(Clone, Copy, Default)]
+struct LinkOpt {
+    force: bool,
+    backup: bool,
+    interactive: bool,
+    relative: bool,
+    dry: bool,
+    privileged: bool,
+}
+
+/// Bookkeeping shared by everything a single invocation does, including the
+/// entries processed concurrently during a sync.
+#[derive(Default)]
+struct Session {
+    state: Mutex<state::State>,
+    backups: Mutex<backup::BackupSet>,
+    batch: Mutex<elevate::Batch>,
+    journal: Mutex<Option<journal::Journal>>,
+    /// Ignore patterns of each entry, for relinking a directory another
+    /// entry folded.
+    ignores: HashMap<String, Vec<String>>,
+}
+
+impl Session {
+    fn new(manifest: &Manifest) -> Result<Self> {
+        let mut ignores: HashMap<String, Vec<String>> = HashMap::new();
+        for (name, entries) in manifest.entries.iter() {
+            for patterns in entries.iter().filter_map(|entry| entry.ignore.as_ref()) {
+                ignores
+                    .entry(name.clone())
+                    .or_default()
+                    .extend(patterns.iter().cloned());
+            }
+        }
+        Ok(Self {
+            state: Mutex::new(state::State::load(&manifest.path)?),
+            ignores,
+            ..Default::default()
+        })
+    }
+}
+
+/// Changes to the filesystem, which are handed over to the batch for
+/// privileged entries and skipped entirely during a dry run. Everything else
+/// is journaled while a journal is kept.
+impl Session {
+    fn state(&self) -> MutexGuard<'_, state::State> {
+        lock(&self.state)
+    }
+
+    fn backups(&self) -> MutexGuard<'_, backup::BackupSet> {
+        lock(&self.backups)
+    }
+
+    fn batch(&self) -> MutexGuard<'_, elevate::Batch> {
+        lock(&self.batch)
+    }
+
+    fn journal(&self) -> MutexGuard<'_, Option<journal::Journal>> {
+        lock(&self.journal)
+    }
+
+    fn remove_file(&self, the_path: &path::Path, opt: &LinkOpt) -> Result<()> {
+        if opt.dry {
+            return Ok(());
+        }
+        if opt.privileged {
+            self.batch().remove(the_path);
+            return Ok(());
+        }
+        if let Some(journal) = self.journal().as_mut() {
+            journal.replacing(the_path)?;
+        }
+        fs::remove_file(the_path)
+            .map_err(|err| format!("could not remove file {}: {err}", the_path.display()).into())
+    }
+
+    /// Like `remove_file`, but a directory is removed with everything in it.
+    fn remove_all(&self, the_path: &path::Path, opt: &LinkOpt) -> Result<()> {
+        let is_dir = fs::symlink_metadata(the_path).is_ok_and(|metadata| metadata.is_dir());
+        if !is_dir {
+            return self.remove_file(the_path, opt);
+        }
+        if opt.dry {
+            return Ok(());
+        }
+        if opt.privileged {
+            self.batch().remove(the_path);
+            return Ok(());
+        }
+        if let Some(journal) = self.journal().as_mut() {
+            journal.replacing(the_path)?;
+        }
+        fs::remove_dir_all(the_path)
+            .map_err(|err| format!("could not remove dir {}: {err}", the_path.display()).into())
+    }
+
+    fn remove_dir(&self, the_path: &path::Path, opt: &LinkOpt) -> Result<()> {
+        if opt.dry {
+            return Ok(());
+        }
+        if opt.privileged {
+            self.batch().remove_dir(the_path);
+            return Ok(());
+        }
+        fs::remove_dir(the_path)
+            .map_err(|err| format!("could not remove dir {}: {err}", the_path.display()))?;
+        if let Some(journal) = self.journal().as_mut() {
+            journal.removed_dir(the_path);
+        }
+        Ok(())
+    }
+
+    fn create_dir(&self, the_path: &path::Path, opt: &LinkOpt) -> Result<()> {
+        if opt.dry {
+            return Ok(());
+        }
+        if opt.privileged {
+            self.batch().create_dir(the_path);
+        } else {
+            fs::create_dir(the_path)
+                .map_err(|err| format!("could not create dir {}: {err}", the_path.display()))?;
+            if let Some(journal) = self.journal().as_mut() {
+                journal.created(the_path);
+            }
+        }
+        self.state().created_dir(the_path);
+        Ok(())
+    }
+
+    fn create_dir_all(&self, the_path: &path::Path, opt: &LinkOpt) -> Result<()> {
+        if opt.dry {
+            return Ok(());
+        }
+        let missing: Vec<path::PathBuf> = the_path
+            .ancestors()
+            .take_while(|dir| !dir.exists())
+            .map(path::Path::to_path_buf)
+            .collect();
+        if opt.privileged {
+            self.batch().create_dir_all(the_path);
+        } else {
+            fs::create_dir_all(the_path)
+                .map_err(|err| format!("could not create dir {}: {err}", the_path.display()))?;
+            if let Some(journal) = self.journal().as_mut() {
+                for dir in missing.iter().rev() {
+                    journal.created(dir);
+                }
+            }
+        }
+        let mut state = self.state();
+        for dir in &missing {
+            state.created_dir(dir);
+        }
+        Ok(())
+    }
+
+    fn symlink(&self, original: &path::Path, link: &path::Path, opt: &LinkOpt) -> Result<()> {
+        if opt.dry {
+            return Ok(());
+        }
+        if opt.privileged {
+            self.batch().symlink(original, link);
+            return Ok(());
+        }
+        symlink_unix(original, link).map_err(|err| {
+            format!(
+                "could not symlink {} to {}: {err}",
+                original.display(),
+                link.display()
+            )
+        })?;
+        if let Some(journal) = self.journal().as_mut() {
+            journal.created(link);
+        }
+        Ok(())
+    }
+
+    fn hard_link(&self, original: &path::Path, link: &path::Path, opt: &LinkOpt) -> Result<()> {
+        if opt.dry {
+            return Ok(());
+        }
+        if opt.privileged {
+            self.batch().hard_link(original, link);
+            return Ok(());
+        }
+        fs::hard_link(original, link).map_err(|err| {
+            format!(
+                "could not hardlink {} to {}: {err}",
+                original.display(),
+                link.display()
+            )
+        })?;
+        if let Some(journal) = self.journal().as_mut() {
+            journal.created(link);
+        }
+        Ok(())
+    }
+
+    fn write(
+        &self,
+        the_path: &path::Path,
+        contents: &[u8],
+        mode: Option<u32>,
+        opt: &LinkOpt,
+    ) -> Result<()> {
+        if opt.dry {
+            return Ok(());
+        }
+        if opt.privileged {
+            return self.batch().write(the_path, contents, mode);
+        }
+        if let Some(journal) = self.journal().as_mut() {
+            journal.replacing(&write_destination(the_path))?;
+        }
+        write_atomic(the_path, contents, mode)
+    }
+
+    fn set_permissions(&self, the_path: &path::Path, mode: u32, opt: &LinkOpt) -> Result<()> {
+        if opt.dry {
+            return Ok(());
+        }
+        if opt.privileged {
+            self.batch().set_permissions(the_path, mode);
+            return Ok(());
+        }
+        if let Some(journal) = self.journal().as_mut() {
+            journal.permissions(the_path);
+        }
+        fs::set_permissions(the_path, fs::Permissions::from_mode(mode)).map_err(|err| {
+            format!("could not set permissions of {}: {err}", the_path.display()).into()
+        })
+    }
+
+    fn execute_hook(&self, cmd: &str) -> Result<()> {
+        execute_hook(cmd)?;
+        if let Some(journal) = self.journal().as_mut() {
+            journal.ran_hook(cmd);
+        }
+        Ok(())
+    }
+
+    fn stash(&self, the_path: &path::Path, opt: &LinkOpt) -> Result<()> {
+        let backup = self.backups().backup_path(the_path, opt.privileged)?;
+        if !opt.privileged {
+            self.backups().stash(the_path, opt.dry)?;
+            if let Some(journal) = self.journal().as_mut().filter(|_| !opt.dry) {
+                journal.moved(the_path, &backup);
+            }
+            return Ok(());
+        }
+        if !opt.dry {
+            self.backups().create_privileged_root()?;
+            self.batch().move_path(the_path, &backup)?;
+        }
+        log!(
+            Info,
+            "Backed up {} to {}",
+            the_path.display(),
+            backup.display()
+        );
+        Ok(())
+    }
+}
+
+/// A panicking entry leaves nothing half-updated that the others could trip
+/// over, so a poisoned lock is as good as any other.
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// Everything templates can refer to.
+#[derive(Default, Serialize)]
+struct TemplateContext {
+    /// The color palette, `wallpaper`, `theme` and the manifest's variables.
+    #[serde(flatten)]
+    values: HashMap<String, String>,
+    /// Facts about the machine, under a name no variable may take.
+    host: Option<&'static facts::Facts>,
+}
+
+impl TemplateContext {
+    fn insert(&mut self, key: String, value: String) {
+        self.values.insert(key, value);
+    }
+}
 
 fn init_template_context(context: &mut TemplateContext, manifest: &Manifest) -> Result<()> {
     if let Some(wallpaper) = &manifest.options.wallpaper {
@@ -80,15 +572,24 @@ fn init_template_context(context: &mut TemplateContext, manifest: &Manifest) ->
 
     if let Some(vars) = &manifest.variables {
         for (k, v) in vars {
+            if k == "host" {
+                return Err(
+                    "invalid variable host: the name is reserved for facts about the machine"
+                        .to_string()
+                        .into(),
+                );
+            }
             context.insert(k.to_string(), v.to_string());
         }
     }
+    context.host = Some(facts::facts());
     Ok(())
 }
 
-impl TryFrom<&path::Path> for Manifest {
-    type Error = Error;
-    fn try_from(value: &path::Path) -> std::result::Result<Self, Self::Error> {
+impl Manifest {
+    /// Reads the manifest at `value` and applies the overrides for this host
+    /// and `profile`.
+    fn load(value: &path::Path, profile: Option<&str>) -> Result<Self> {
         let path = value
             .canonicalize()
             .map_err(|err| format!("invalid path {}: {err}", value.display()))?;
@@ -101,13 +602,255 @@ impl TryFrom<&path::Path> for Manifest {
                 parent_dir.display()
             )
         })?;
-        let manifest: Manifest = toml::from_str(
+        let mut manifest: Manifest = toml::from_str(
             &fs::read_to_string(&path)
                 .map_err(|err| format!("could not read file {}: {err}", path.display()))?,
         )
         .map_err(|err| format!("could not parse toml {}: {err}", path.display()))?;
+        manifest.record_origins(&path);
+        if let Some(patterns) = manifest.include.take() {
+            manifest.include(&path, &patterns, &mut vec![path.clone()])?;
+        }
+        manifest.path = path;
+        manifest
+            .apply_profiles(profile)
+            .and_then(|()| manifest.interpolate())
+            .map_err(|err| format!("invalid manifest {}: {err}", manifest.path.display()))?;
+        manifest.entries = sort_entries(mem::take(&mut manifest.entries), &manifest.sources)
+            .map_err(|err| format!("invalid manifest {}: {err}", manifest.path.display()))?;
         Ok(manifest)
     }
+
+    /// Notes `file` as where the entries and variables read so far were
+    /// defined.
+    fn record_origins(&mut self, file: &path::Path) {
+        for (name, entries) in self.entries.iter_mut() {
+            self.sources.insert(name.clone(), file.to_path_buf());
+            for entry in entries {
+                entry.origin = file.to_path_buf();
+            }
+        }
+        for name in self.variables.iter().flat_map(IndexMap::keys) {
+            self.variable_sources
+                .insert(name.clone(), file.to_path_buf());
+        }
+    }
+
+    /// Merges the fragments matching `patterns`, relative to `file`, into the
+    /// manifest. Names of entries and variables have to be unique across all
+    /// files.
+    fn include(
+        &mut self,
+        file: &path::Path,
+        patterns: &[String],
+        included: &mut Vec<path::PathBuf>,
+    ) -> Result<()> {
+        let dir = file
+            .parent()
+            .ok_or(format!("could not access parent dir of {}", file.display()))?;
+        for pattern in patterns {
+            let mut matches = glob::glob(&dir.join(pattern).to_string_lossy())
+                .map_err(|err| format!("invalid include {pattern} in {}: {err}", file.display()))?
+                .collect::<std::result::Result<Vec<_>, _>>()
+                .map_err(|err| {
+                    format!("could not read {}: {}", err.path().display(), err.error())
+                })?;
+            if matches.is_empty() {
+                return Err(format!(
+                    "include {pattern} in {} did not match any files",
+                    file.display()
+                )
+                .into());
+            }
+            matches.sort();
+
+            for fragment_path in matches {
+                let fragment_path = fragment_path
+                    .canonicalize()
+                    .map_err(|err| format!("invalid path {}: {err}", fragment_path.display()))?;
+                if included.contains(&fragment_path) {
+                    return Err(
+                        format!("{} is included more than once", fragment_path.display()).into(),
+                    );
+                }
+                included.push(fragment_path.clone());
+
+                let fragment: Fragment =
+                    toml::from_str(&fs::read_to_string(&fragment_path).map_err(|err| {
+                        format!("could not read file {}: {err}", fragment_path.display())
+                    })?)
+                    .map_err(|err| {
+                        format!("could not parse toml {}: {err}", fragment_path.display())
+                    })?;
+
+                for (name, mut entries) in fragment.entries {
+                    if let Some(source) = self.sources.get(&name) {
+                        return Err(format!(
+                            "entry {name} in {} is already defined in {}",
+                            fragment_path.display(),
+                            source.display()
+                        )
+                        .into());
+                    }
+                    self.sources.insert(name.clone(), fragment_path.clone());
+                    for entry in entries.iter_mut() {
+                        entry.origin = fragment_path.clone();
+                    }
+                    self.entries.insert(name, entries);
+                }
+                if let Some(variables) = fragment.variables {
+                    let defined = self.variables.get_or_insert_default();
+                    for (name, value) in variables {
+                        if let Some(source) = self.variable_sources.get(&name) {
+                            return Err(format!(
+                                "variable {name} in {} is already defined in {}",
+                                fragment_path.display(),
+                                source.display()
+                            )
+                            .into());
+                        }
+                        self.variable_sources
+                            .insert(name.clone(), fragment_path.clone());
+                        defined.insert(name, value);
+                    }
+                }
+                if let Some(patterns) = fragment.include {
+                    self.include(&fragment_path, &patterns, included)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Layers the overrides in `[hosts.HOSTNAME]` onto the manifest, then the
+    /// ones in `[profiles.PROFILE]`.
+    fn apply_profiles(&mut self, profile: Option<&str>) -> Result<()> {
+        let hostname = &facts::facts().hostname;
+        if let Some(host) = self.hosts.shift_remove(hostname) {
+            self.apply_profile(host)
+                .map_err(|err| format!("invalid overrides for host {hostname}: {err}"))?;
+        }
+        if let Some(name) = profile {
+            let overrides = self
+                .profiles
+                .shift_remove(name)
+                .ok_or(format!("could not find profile {name}"))?;
+            self.apply_profile(overrides)
+                .map_err(|err| format!("invalid overrides for profile {name}: {err}"))?;
+        }
+        Ok(())
+    }
+
+    /// Expands `${...}` in the variables, and in the paths and hooks of every
+    /// entry.
+    fn interpolate(&mut self) -> Result<()> {
+        let Some(variables) = &self.variables else {
+            return self.interpolate_entries(&IndexMap::new());
+        };
+        let variables = interpolate::resolve_variables(variables)?;
+        self.interpolate_entries(&variables)?;
+        self.variables = Some(variables);
+        Ok(())
+    }
+
+    fn interpolate_entries(&mut self, variables: &IndexMap<String, String>) -> Result<()> {
+        for (name, entries) in self.entries.iter_mut() {
+            // Entries for other machines may refer to what only exists there.
+            for entry in entries.iter_mut().filter(|entry| entry.applies()) {
+                entry
+                    .interpolate(variables)
+                    .and_then(|()| match entry.origin == self.path {
+                        true => Ok(()),
+                        false => entry.rebase(),
+                    })
+                    .and_then(|()| entry.validate())
+                    .map_err(|err| format!("invalid entry {}: {err}", entry.label(name)))?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Options and variables are overridden one by one, entries as a whole.
+    fn apply_profile(&mut self, overrides: Profile) -> Result<()> {
+        if let Some(options) = overrides.options {
+            let mut merged = toml::Table::try_from(&self.options)
+                .map_err(|err| format!("could not serialize options: {err}"))?;
+            merged.extend(options);
+            self.options = merged
+                .try_into()
+                .map_err(|err| format!("invalid options: {err}"))?;
+        }
+        if let Some(variables) = overrides.variables {
+            self.variables.get_or_insert_default().extend(variables);
+        }
+        if let Some(entries) = overrides.entries {
+            for (name, mut entries) in entries {
+                self.sources
+                    .entry(name.clone())
+                    .or_insert_with(|| self.path.clone());
+                for entry in entries.iter_mut() {
+                    entry.origin = self.path.clone();
+                }
+                self.entries.insert(name, entries);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Orders the entries so that every entry comes after the ones it depends
+/// on, keeping the order of the manifest otherwise.
+fn sort_entries(
+    mut entries: IndexMap<String, Vec<Entry>>,
+    sources: &HashMap<String, path::PathBuf>,
+) -> Result<IndexMap<String, Vec<Entry>>> {
+    let mut order = Vec::with_capacity(entries.len());
+    let mut visiting = Vec::new();
+    for name in entries.keys() {
+        visit_entry(name, &entries, sources, &mut visiting, &mut order)?;
+    }
+
+    let mut sorted = IndexMap::with_capacity(entries.len());
+    for name in order {
+        if let Some(entry) = entries.shift_remove(&name) {
+            sorted.insert(name, entry);
+        }
+    }
+    Ok(sorted)
+}
+
+fn visit_entry(
+    name: &str,
+    entries: &IndexMap<String, Vec<Entry>>,
+    sources: &HashMap<String, path::PathBuf>,
+    visiting: &mut Vec<String>,
+    order: &mut Vec<String>,
+) -> Result<()> {
+    if order.iter().any(|done| done == name) {
+        return Ok(());
+    }
+    if let Some(pos) = visiting.iter().position(|seen| seen == name) {
+        let cycle = visiting[pos..].join(" -> ");
+        return Err(format!("dependency cycle between entries: {cycle} -> {name}").into());
+    }
+
+    visiting.push(name.to_string());
+    for dependency in entries[name]
+        .iter()
+        .flat_map(|entry| entry.depends_on.iter().flatten())
+    {
+        if !entries.contains_key(dependency) {
+            return Err(format!(
+                "{name} in {} depends on {dependency}, which does not exist",
+                sources[name].display()
+            )
+            .into());
+        }
+        visit_entry(dependency, entries, sources, visiting, order)?;
+    }
+    visiting.pop();
+    order.push(name.to_string());
+    Ok(())
 }
 
 fn main() {
@@ -117,176 +860,560 @@ fn main() {
     }
 }
 
+/// Installs the entries of `name`, or all of them, and runs their hooks.
+/// Entries are processed on up to `jobs` threads, each one only after those
+/// it has to wait for. Returns which entries had templates and whether they
+/// changed.
+fn sync_entries(
+    manifest: &Manifest,
+    name_filter: Option<&str>,
+    opt: &LinkOpt,
+    jobs: usize,
+    context: &TemplateContext,
+    template_engine: &upon::Engine,
+    session: &Session,
+) -> Result<Vec<(String, bool)>> {
+    let names: Vec<&String> = manifest
+        .entries
+        .keys()
+        .filter(|name| name_filter.is_none_or(|name_filter| name_filter == *name))
+        .collect();
+    let waits_for = entry_dependencies(manifest, &names)?;
+    let templates = schedule::run(names.len(), &waits_for, jobs, |index| {
+        let name = names[index];
+        sync_entry(
+            name,
+            &manifest.entries[name],
+            opt,
+            context,
+            template_engine,
+            session,
+        )
+    })?;
+    Ok(templates.into_iter().flatten().collect())
+}
+
+/// For each of `names`, the earlier ones it has to wait for: the ones it
+/// depends on and the ones installing into an overlapping destination.
+fn entry_dependencies(manifest: &Manifest, names: &[&String]) -> Result<Vec<Vec<usize>>> {
+    let mut dests = Vec::with_capacity(names.len());
+    for name in names {
+        let mut entry_dests = Vec::new();
+        for entry in &manifest.entries[*name] {
+            entry_dests.push(path::absolute(resolve_home_dir(&entry.dest)?)?);
+        }
+        dests.push(entry_dests);
+    }
+
+    let overlaps = |a: usize, b: usize| {
+        dests[a].iter().any(|dest: &path::PathBuf| {
+            dests[b]
+                .iter()
+                .any(|other| dest.starts_with(other) || other.starts_with(dest))
+        })
+    };
+    let depends_on = |a: usize, b: usize| {
+        manifest.entries[names[a]]
+            .iter()
+            .flat_map(|entry| entry.depends_on.iter().flatten())
+            .any(|dependency| dependency == names[b])
+    };
+    // Dependencies always come first, the entries are sorted accordingly.
+    Ok((0..names.len())
+        .map(|index| {
+            (0..index)
+                .filter(|&other| depends_on(index, other) || overlaps(index, other))
+                .collect()
+        })
+        .collect())
+}
+
+fn sync_entry(
+    name: &str,
+    entries: &[Entry],
+    opt: &LinkOpt,
+    context: &TemplateContext,
+    template_engine: &upon::Engine,
+    session: &Session,
+) -> Result<Vec<(String, bool)>> {
+    let mut templates = Vec::new();
+    for entry in entries {
+        if !check_conditions(name, entry, opt.dry) {
+            continue;
+        }
+        if let Some(pre_hook) = &entry.pre_hooks {
+            for cmd in pre_hook.iter() {
+                log!(Info, "Executing pre-hook in {}: {}", name, cmd);
+                if !opt.dry {
+                    session.execute_hook(cmd)?;
+                }
+            }
+        }
+
+        if let Some(target) = &entry.target {
+            link_target(name, entry, target, opt, session).map_err(|err| {
+                format!(
+                    "something went wrong while symlinking {}:\n    {err}",
+                    entry.label(name)
+                )
+            })?;
+        }
+
+        if let Some(template) = &entry.template {
+            let changed = generate_template(
+                name,
+                entry,
+                template,
+                context,
+                template_engine,
+                opt.dry,
+                session,
+            )
+            .map_err(|err| {
+                format!(
+                    "something went wrong while generating {}:\n    {err}",
+                    entry.label(name)
+                )
+            })?;
+            templates.push((name.to_string(), changed));
+        }
+
+        if let Some(post_hook) = &entry.post_hooks {
+            for cmd in post_hook.iter() {
+                log!(Info, "Executing post-hook in {}: {}", name, cmd);
+                if !opt.dry {
+                    // Privileged files are only in place once the batch
+                    // has run.
+                    if entry.privileged {
+                        session.batch().defer_hook(cmd);
+                    } else {
+                        session.execute_hook(cmd)?;
+                    }
+                }
+            }
+        }
+    }
+    Ok(templates)
+}
+
 fn entrypoint() -> Result<()> {
-    let args = cli::Cli::try_parse()?;
+    let mut args = cli::Cli::try_parse()?;
+    // Loading the manifest changes into its directory, relative paths given
+    // on the command line are relative to where tread was started.
+    if let cli::SubCommand::Adopt { ref mut path, .. } = args.subcommand {
+        *path = path::absolute(resolve_home_dir(&path)?)?;
+    }
 
-    let manifest = Manifest::try_from(args.manifest_path.as_path())?;
-    let mut context: TemplateContext = HashMap::new();
+    let manifest = Manifest::load(&args.manifest_path, args.profile.as_deref())?;
+    let session = Session::new(&manifest)?;
+    let mut context = TemplateContext::default();
 
-    let mut template_engine = upon::Engine::new();
+    let template_engine = upon::Engine::new();
 
     match args.subcommand {
         cli::SubCommand::Sync {
             force,
+            backup,
+            interactive,
             dry,
+            prune,
+            jobs,
             ref name,
         } => {
+            // Prompts of concurrent entries would get in each other's way.
+            let jobs = if interactive {
+                1
+            } else {
+                jobs.unwrap_or_else(|| thread::available_parallelism().map_or(1, usize::from))
+            };
+            let link_opt = LinkOpt {
+                force,
+                backup: backup || manifest.options.backup,
+                interactive,
+                relative: manifest.options.relative,
+                dry,
+                privileged: false,
+            };
             if dry {
                 log!(Warning, "Performing a dry run.");
             }
             if let Some(name) = name {
                 if let Some(entries) = manifest.entries.get(name) {
-                    for entry in entries {
-                        if let Some(pre_hook) = &entry.pre_hooks {
-                            for cmd in pre_hook.iter() {
-                                log!(Info, "Executing pre-hook in {}: {}", name, cmd);
-                                if !dry {
-                                    execute_hook(cmd)?;
-                                }
-                            }
-                        }
+                    if entries
+                        .iter()
+                        .any(|entry| entry.applies() && entry.template.is_some())
+                    {
+                        init_template_context(&mut context, &manifest)?;
+                    }
+                } else {
+                    return Err(format!("could not find {}", &name).into());
+                }
+            } else if has_templates(&manifest) {
+                init_template_context(&mut context, &manifest)?;
+            }
+
+            if dry {
+                let templates = sync_entries(
+                    &manifest,
+                    name.as_deref(),
+                    &link_opt,
+                    jobs,
+                    &context,
+                    &template_engine,
+                    &session,
+                )?;
+                report_templates(&templates);
+                if prune {
+                    prune_outputs(&manifest, dry, &session)?;
+                }
+                return Ok(());
+            }
+
+            // Everything is checked in a dry run first, so that problems show
+            // up before anything is touched.
+            helper::QUIET.store(true, Ordering::Relaxed);
+            let plan = Session::new(&manifest)?;
+            let plan_opt = LinkOpt {
+                interactive: false,
+                dry: true,
+                ..link_opt
+            };
+            let planned = sync_entries(
+                &manifest,
+                name.as_deref(),
+                &plan_opt,
+                1,
+                &context,
+                &template_engine,
+                &plan,
+            )
+            .and_then(|_| match prune {
+                true => prune_outputs(&manifest, true, &plan),
+                false => Ok(()),
+            });
+            helper::QUIET.store(false, Ordering::Relaxed);
+            planned?;
 
+            *session.journal() = Some(journal::Journal::new(state::state_dir()?));
+            let applied = sync_entries(
+                &manifest,
+                name.as_deref(),
+                &link_opt,
+                jobs,
+                &context,
+                &template_engine,
+                &session,
+            )
+            .and_then(|templates| {
+                if prune {
+                    prune_outputs(&manifest, dry, &session)?;
+                }
+                session.batch().run(&manifest.options.elevate)?;
+                Ok(templates)
+            });
+            let journal = session.journal().take();
+            match applied {
+                Ok(templates) => {
+                    if let Some(journal) = journal {
+                        journal.discard();
+                    }
+                    report_templates(&templates);
+                }
+                Err(err) => {
+                    // Rolling back only some of the changes would leave a
+                    // mix of old and new files behind.
+                    let committed = session.batch().is_committed();
+                    if committed {
+                        log!(
+                            Error,
+                            "privileged operations already took effect, so nothing is rolled back"
+                        );
+                    }
+                    if let Some(journal) = journal {
+                        match committed {
+                            true => journal.discard(),
+                            false => journal.rollback(),
+                        }
+                    }
+                    return Err(err);
+                }
+            }
+        }
+        cli::SubCommand::Prune { dry } => {
+            if dry {
+                log!(Warning, "Performing a dry run.");
+            }
+            prune_outputs(&manifest, dry, &session)?;
+        }
+        cli::SubCommand::Link {
+            force,
+            backup,
+            interactive,
+            dry,
+            ref name,
+        } => {
+            let link_opt = LinkOpt {
+                force,
+                backup: backup || manifest.options.backup,
+                interactive,
+                relative: manifest.options.relative,
+                dry,
+                privileged: false,
+            };
+            if dry {
+                log!(Warning, "Performing a dry run.");
+            }
+            if let Some(name) = name {
+                if let Some(entries) = manifest.entries.get(name) {
+                    for entry in entries {
+                        if !check_conditions(name, entry, dry) {
+                            continue;
+                        }
                         if let Some(target) = &entry.target {
-                            symlink_dir_all(target, &entry.dest, force, dry, entry.recursive)
-                                .map_err(|err| {
+                            link_target(name, entry, target, &link_opt, &session).map_err(
+                                |err| {
                                     format!(
-                                        "something went wrong while symlinking {name}:\n    {err}"
+                                        "something went wrong while symlinking {}:\n    {err}",
+                                        entry.label(name)
                                     )
-                                })?;
-                        }
-
-                        if let Some(template) = &entry.template {
-                            init_template_context(&mut context, &manifest)?;
-                            generate_template(
-                                &entry.dest,
-                                template,
-                                &context,
-                                &mut template_engine,
-                                dry,
-                            )
-                            .map_err(|err| {
-                                format!("something went wrong while generating {name}:\n    {err}")
-                            })?;
-                        }
-
-                        if let Some(post_hook) = &entry.post_hooks {
-                            for cmd in post_hook.iter() {
-                                log!(Info, "Executing post-hook in {}: {}", name, cmd);
-                                if !dry {
-                                    execute_hook(cmd)?;
-                                }
-                            }
+                                },
+                            )?;
                         }
                     }
                 } else {
                     return Err(format!("could not find {}", &name).into());
                 }
             } else {
-                if has_templates(&manifest) {
-                    init_template_context(&mut context, &manifest)?;
-                }
                 for (name, entries) in manifest.entries.iter() {
                     for entry in entries {
-                        if let Some(pre_hook) = &entry.pre_hooks {
-                            for cmd in pre_hook.iter() {
-                                log!(Info, "Executing pre-hook in {}: {}", name, cmd);
-                                if !dry {
-                                    execute_hook(cmd)?;
-                                }
-                            }
+                        if !check_conditions(name, entry, dry) {
+                            continue;
                         }
-
                         if let Some(target) = &entry.target {
-                            symlink_dir_all(target, &entry.dest, force, dry, entry.recursive)
-                                .map_err(|err| {
+                            link_target(name, entry, target, &link_opt, &session).map_err(
+                                |err| {
                                     format!(
-                                        "something went wrong while symlinking {name}:\n    {err}"
+                                        "something went wrong while symlinking {}:\n    {err}",
+                                        entry.label(name)
                                     )
-                                })?;
+                                },
+                            )?;
                         }
-
-                        if let Some(template) = &entry.template {
-                            generate_template(
-                                &entry.dest,
-                                template,
-                                &context,
-                                &mut template_engine,
-                                dry,
-                            )
-                            .map_err(|err| {
-                                format!("something went wrong while generating {name}:\n    {err}")
+                    }
+                }
+            }
+        }
+        cli::SubCommand::Unlink { dry, ref name } => {
+            if dry {
+                log!(Warning, "Performing a dry run.");
+            }
+            if let Some(name) = name {
+                if let Some(entries) = manifest.entries.get(name) {
+                    for entry in entries {
+                        if !check_conditions(name, entry, dry) {
+                            continue;
+                        }
+                        if let Some(target) = &entry.target {
+                            unlink_target(entry, target, dry, &session).map_err(|err| {
+                                format!(
+                                    "something went wrong while unlinking {}:\n    {err}",
+                                    entry.label(name)
+                                )
                             })?;
                         }
-
-                        if let Some(post_hook) = &entry.post_hooks {
-                            for cmd in post_hook.iter() {
-                                log!(Info, "Executing post-hook in {}: {}", name, cmd);
-                                if !dry {
-                                    execute_hook(cmd)?;
-                                }
-                            }
+                    }
+                } else {
+                    return Err(format!("could not find {}", &name).into());
+                }
+            } else {
+                for (name, entries) in manifest.entries.iter() {
+                    for entry in entries {
+                        if !check_conditions(name, entry, dry) {
+                            continue;
+                        }
+                        if let Some(target) = &entry.target {
+                            unlink_target(entry, target, dry, &session).map_err(|err| {
+                                format!(
+                                    "something went wrong while unlinking {}:\n    {err}",
+                                    entry.label(name)
+                                )
+                            })?;
                         }
                     }
                 }
             }
         }
-        cli::SubCommand::Link {
-            force,
-            dry,
+        cli::SubCommand::Restore { list, dry, ref id } => {
+            if list {
+                for id in backup::list()? {
+                    println!("{id}");
+                }
+            } else {
+                if dry {
+                    log!(Warning, "Performing a dry run.");
+                }
+                backup::restore(
+                    id.as_deref(),
+                    dry,
+                    &mut session.state(),
+                    &mut session.batch(),
+                )?;
+            }
+        }
+        cli::SubCommand::Adopt {
+            ref path,
             ref name,
+            dry,
         } => {
             if dry {
                 log!(Warning, "Performing a dry run.");
             }
+            let link_opt = LinkOpt {
+                force: false,
+                backup: false,
+                interactive: false,
+                relative: manifest.options.relative,
+                dry,
+                privileged: false,
+            };
+            adopt(&manifest, path, name.as_deref(), &link_opt, &session)?;
+        }
+        cli::SubCommand::Status { ref name } => {
+            if let Some(name) = name {
+                if let Some(entries) = manifest.entries.get(name) {
+                    if entries
+                        .iter()
+                        .any(|entry| entry.applies() && entry.template.is_some())
+                    {
+                        init_template_context(&mut context, &manifest)?;
+                    }
+                } else {
+                    return Err(format!("could not find {}", &name).into());
+                }
+            } else if has_templates(&manifest) {
+                init_template_context(&mut context, &manifest)?;
+            }
+            let rows = status::collect(
+                &manifest,
+                name.as_deref(),
+                &context,
+                &template_engine,
+                &session.state(),
+            )?;
+            status::print_table(&rows);
+
+            let out_of_sync = rows
+                .iter()
+                .filter(|row| row.status != status::Status::UpToDate)
+                .count();
+            if out_of_sync > 0 {
+                return Err(format!(
+                    "{out_of_sync} of {} destinations are out of sync",
+                    rows.len()
+                )
+                .into());
+            }
+        }
+        cli::SubCommand::Manifest => {
+            let effective = toml::to_string_pretty(&manifest)
+                .map_err(|err| format!("could not serialize manifest: {err}"))?;
+            print!("{effective}");
+        }
+        cli::SubCommand::Diff { ref name } => {
+            let mut changed = 0;
             if let Some(name) = name {
                 if let Some(entries) = manifest.entries.get(name) {
                     for entry in entries {
-                        if let Some(target) = &entry.target {
-                            symlink_dir_all(target, &entry.dest, force, dry, entry.recursive)
+                        if !check_conditions(name, entry, true) {
+                            continue;
+                        }
+                        if let Some(template) = &entry.template {
+                            init_template_context(&mut context, &manifest)?;
+                            if diff_template(&entry.dest, template, &context, &template_engine)
                                 .map_err(|err| {
                                     format!(
-                                        "something went wrong while symlinking {name}:\n    {err}"
+                                        "something went wrong while diffing {}:\n    {err}",
+                                        entry.label(name)
                                     )
-                                })?;
+                                })?
+                            {
+                                changed += 1;
+                            }
                         }
                     }
                 } else {
                     return Err(format!("could not find {}", &name).into());
                 }
             } else {
+                if has_templates(&manifest) {
+                    init_template_context(&mut context, &manifest)?;
+                }
                 for (name, entries) in manifest.entries.iter() {
                     for entry in entries {
-                        if let Some(target) = &entry.target {
-                            symlink_dir_all(target, &entry.dest, force, dry, entry.recursive)
+                        if !check_conditions(name, entry, true) {
+                            continue;
+                        }
+                        if let Some(template) = &entry.template {
+                            if diff_template(&entry.dest, template, &context, &template_engine)
                                 .map_err(|err| {
                                     format!(
-                                        "something went wrong while symlinking {name}:\n    {err}"
+                                        "something went wrong while diffing {}:\n    {err}",
+                                        entry.label(name)
                                     )
-                                })?;
+                                })?
+                            {
+                                changed += 1;
+                            }
                         }
                     }
                 }
             }
+            if changed == 0 {
+                log!(Info, "Templates are up to date.");
+            }
         }
-        cli::SubCommand::Generate { dry, ref name } => {
+        cli::SubCommand::Generate {
+            dry,
+            diff,
+            ref name,
+        } => {
             if dry {
                 log!(Warning, "Performing a dry run.");
             }
+            let mut templates = Vec::new();
             if let Some(name) = name {
                 if let Some(entries) = manifest.entries.get(name) {
                     for entry in entries {
+                        if !check_conditions(name, entry, dry) {
+                            continue;
+                        }
                         if let Some(template) = &entry.template {
                             init_template_context(&mut context, &manifest)?;
-                            generate_template(
-                                &entry.dest,
+                            if diff {
+                                diff_template(&entry.dest, template, &context, &template_engine)
+                                    .map_err(|err| {
+                                        format!(
+                                            "something went wrong while diffing {}:\n    {err}",
+                                            entry.label(name)
+                                        )
+                                    })?;
+                            }
+                            let changed = generate_template(
+                                name,
+                                entry,
                                 template,
                                 &context,
-                                &mut template_engine,
+                                &template_engine,
                                 dry,
+                                &session,
                             )
                             .map_err(|err| {
-                                format!("something went wrong while generating {name}:\n    {err}")
+                                format!(
+                                    "something went wrong while generating {}:\n    {err}",
+                                    entry.label(name)
+                                )
                             })?;
+                            templates.push((name.to_string(), changed));
                         }
                     }
                 } else {
@@ -298,31 +1425,76 @@ fn entrypoint() -> Result<()> {
                 }
                 for (name, entries) in manifest.entries.iter() {
                     for entry in entries {
+                        if !check_conditions(name, entry, dry) {
+                            continue;
+                        }
                         if let Some(template) = &entry.template {
-                            generate_template(
-                                &entry.dest,
+                            if diff {
+                                diff_template(&entry.dest, template, &context, &template_engine)
+                                    .map_err(|err| {
+                                        format!(
+                                            "something went wrong while diffing {}:\n    {err}",
+                                            entry.label(name)
+                                        )
+                                    })?;
+                            }
+                            let changed = generate_template(
+                                name,
+                                entry,
                                 template,
                                 &context,
-                                &mut template_engine,
+                                &template_engine,
                                 dry,
+                                &session,
                             )
                             .map_err(|err| {
-                                format!("something went wrong while generating {name}:\n    {err}")
+                                format!(
+                                    "something went wrong while generating {}:\n    {err}",
+                                    entry.label(name)
+                                )
                             })?;
+                            templates.push((name.to_string(), changed));
                         }
                     }
                 }
             }
+            report_templates(&templates);
         }
     }
 
+    if !args.subcommand.is_dry() {
+        session.batch().run(&manifest.options.elevate)?;
+        session.state().save()?;
+    }
+
     Ok(())
 }
 
+/// Whether `entry` applies to this machine. Skipped entries are reported, and
+/// during a dry run so are the conditions of the ones that apply.
+fn check_conditions(name: &str, entry: &Entry, dry: bool) -> bool {
+    let Some(when) = &entry.when else {
+        return true;
+    };
+    if !entry.applies() {
+        log!(
+            Info,
+            "Skipping {name}, it only applies when {}",
+            when.describe()
+        );
+        return false;
+    }
+    if dry {
+        log!(Info, "Including {name}, since {}", when.describe());
+    }
+    true
+}
+
+/// Whether any entry that applies on this machine has a template.
 fn has_templates(manifest: &Manifest) -> bool {
     for (_, entries) in manifest.entries.iter() {
         for entry in entries {
-            if entry.template.is_some() {
+            if entry.applies() && entry.template.is_some() {
                 return true;
             }
         }
@@ -342,75 +1514,453 @@ fn resolve_home_dir(the_path: impl AsRef<path::Path>) -> Result<path::PathBuf> {
     }
 }
 
+/// Pairs of canonical target and destination an entry links. A target
+/// containing glob patterns yields one pair per match that isn't ignored,
+/// placed inside the `dest` directory. As in a shell, `*` only matches hidden
+/// files when the pattern starts with a dot.
+fn expand_target(
+    entry: &Entry,
+    target: &path::Path,
+) -> Result<Vec<(path::PathBuf, path::PathBuf)>> {
+    let dest = &entry.dest;
+    let pattern = resolve_home_dir(target)?;
+    let Some(pattern) = pattern
+        .to_str()
+        .filter(|pattern| pattern.contains(['*', '?', '[']))
+    else {
+        let target = pattern
+            .canonicalize()
+            .map_err(|err| format!("could not find {}: {err}", target.display()))?;
+        return Ok(vec![(target, dest.to_path_buf())]);
+    };
+
+    let options = glob::MatchOptions {
+        require_literal_leading_dot: true,
+        ..Default::default()
+    };
+    let mut matches = glob::glob_with(pattern, options)
+        .map_err(|err| format!("invalid glob pattern {}: {err}", target.display()))?
+        .collect::<std::result::Result<Vec<_>, _>>()
+        .map_err(|err| format!("could not read {}: {}", err.path().display(), err.error()))?;
+    if matches.is_empty() {
+        return Err(format!("{} did not match any files", target.display()).into());
+    }
+    matches.sort();
+
+    // The ignore rules of a glob are relative to the directory it starts in.
+    let base = path::Path::new(pattern)
+        .ancestors()
+        .find(|base| !base.to_string_lossy().contains(['*', '?', '[']))
+        .filter(|base| !base.as_os_str().is_empty())
+        .unwrap_or(path::Path::new("."))
+        .canonicalize()
+        .map_err(|err| format!("could not find {}: {err}", target.display()))?;
+    let rules = entry_ignore_rules(entry, &base)?.descend(&base)?;
+
+    let mut pairs = Vec::with_capacity(matches.len());
+    for matched in matches {
+        let file_name = matched.file_name().ok_or(format!(
+            "could not extract file_name of {}",
+            matched.display()
+        ))?;
+        let dest = dest.join(file_name);
+        let matched = matched
+            .canonicalize()
+            .map_err(|err| format!("could not find {}: {err}", matched.display()))?;
+        if rules.is_ignored(&matched, matched.is_dir()) {
+            continue;
+        }
+        pairs.push((matched, dest));
+    }
+    Ok(pairs)
+}
+
+fn link_target(
+    name: &str,
+    entry: &Entry,
+    target: &path::Path,
+    opt: &LinkOpt,
+    session: &Session,
+) -> Result<()> {
+    let opt = &LinkOpt {
+        privileged: entry.privileged,
+        ..*opt
+    };
+    for (target, dest) in expand_target(entry, target)? {
+        let rules = entry_ignore_rules(entry, &target)?;
+        let relative = entry.relative.unwrap_or(opt.relative);
+        unfold_ancestors(
+            &path::absolute(resolve_home_dir(&dest)?)?,
+            relative,
+            opt,
+            session,
+        )?;
+        symlink_dir_all(name, entry, target, dest, &rules, opt, session)?;
+    }
+    Ok(())
+}
+
+fn entry_ignore_rules(entry: &Entry, target: &path::Path) -> Result<ignore::IgnoreRules> {
+    ignore::IgnoreRules::new(target, entry.ignore.as_deref().unwrap_or_default())
+}
+
 fn symlink_dir_all(
+    name: &str,
+    entry: &Entry,
     target: impl AsRef<path::Path>,
     dest: impl AsRef<path::Path>,
-    force: bool,
-    dry: bool,
-    recursive: bool,
+    rules: &ignore::IgnoreRules,
+    opt: &LinkOpt,
+    session: &Session,
 ) -> Result<()> {
     let target = resolve_home_dir(&target)?
         .canonicalize()
         .map_err(|err| format!("could not find {}: {err}", target.as_ref().display()))?;
-    let dest = resolve_home_dir(dest)?;
+    let dest = path::absolute(resolve_home_dir(dest)?)?;
+
+    let relative = entry.relative.unwrap_or(opt.relative);
+
+    if target.is_dir() && entry.walks_dirs() {
+        let rules = rules.descend(&target)?;
+
+        if entry.fold && entry.mode == InstallMode::Symlink {
+            let folded = dest.is_symlink() && dest.canonicalize().ok().as_ref() == Some(&target);
+            let vacant = !dest.exists() && !dest.is_symlink();
+            if folded || (vacant && !contains_ignored(&target, &rules)?) {
+                return symlink_file(
+                    name,
+                    &target,
+                    &dest,
+                    relative,
+                    entry.dir_permissions()?,
+                    opt,
+                    session,
+                );
+            }
+        }
+        if dest.is_symlink() && dest.is_dir() {
+            unfold(&dest, relative, opt, session)?;
+        }
 
-    if target.is_dir() && recursive {
-        for entry in fs::read_dir(target)? {
-            let entry = entry?;
-            let dest = &dest.join(entry.path().file_name().ok_or(format!(
+        for child in fs::read_dir(&target)? {
+            let child = child?;
+            if rules.is_ignored(&child.path(), child.path().is_dir()) {
+                continue;
+            }
+            let dest = &dest.join(child.path().file_name().ok_or(format!(
                 "could not extract file_name of {}",
-                entry.path().display()
+                child.path().display()
             ))?);
-            let dest_parent_dir = dest
-                .parent()
-                .ok_or(format!("could not access parent dir of {}", dest.display()))?;
-            if !dest_parent_dir.exists() && !dry {
-                fs::create_dir_all(dest_parent_dir).map_err(|err| {
-                    format!("could not create dir {}: {err}", dest_parent_dir.display())
-                })?;
-            }
-            symlink_dir_all(entry.path(), dest, force, dry, recursive)?;
+            create_parent_dirs(dest, entry.dir_permissions()?, opt, session)?;
+            symlink_dir_all(name, entry, child.path(), dest, &rules, opt, session)?;
         }
     } else {
-        symlink_file(&target, &dest, force, dry)?;
+        match entry.mode {
+            InstallMode::Symlink => symlink_file(
+                name,
+                &target,
+                &dest,
+                relative,
+                entry.dir_permissions()?,
+                opt,
+                session,
+            )?,
+            InstallMode::Copy => copy_file(name, entry, &target, &dest, opt, session)?,
+            InstallMode::Hardlink => {
+                hardlink_file(name, &target, &dest, entry.dir_permissions()?, opt, session)?
+            }
+        }
+    }
+    Ok(())
+}
+
+fn contains_ignored(dir: &path::Path, rules: &ignore::IgnoreRules) -> Result<bool> {
+    for child in fs::read_dir(dir)? {
+        let child = child?;
+        let is_dir = child.path().is_dir();
+        if rules.is_ignored(&child.path(), is_dir)
+            || (is_dir && contains_ignored(&child.path(), &rules.descend(&child.path())?)?)
+        {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+/// Replaces a directory symlink that tread folded earlier with a real
+/// directory holding one symlink per child, so that another entry can share
+/// the destination. Symlinks tread does not own are left alone.
+/// Unfolds the directory symlinks tread folded among the ancestors of `dest`,
+/// so that nothing gets installed into the dotfiles through them.
+fn unfold_ancestors(
+    dest: &path::Path,
+    relative: bool,
+    opt: &LinkOpt,
+    session: &Session,
+) -> Result<()> {
+    let ancestors: Vec<&path::Path> = dest.ancestors().skip(1).collect();
+    // Outermost first, an unfolded directory may hold another folded one.
+    for dir in ancestors.into_iter().rev() {
+        if dir.is_symlink() && dir.is_dir() {
+            unfold(dir, relative, opt, session)?;
+        }
+    }
+    Ok(())
+}
+
+fn unfold(dest: &path::Path, relative: bool, opt: &LinkOpt, session: &Session) -> Result<()> {
+    let Some(output) = session.state().get(dest).cloned() else {
+        return Ok(());
+    };
+    let symlink_path = fs::read_link(dest)
+        .map_err(|err| format!("could not read symlink {}: {err}", dest.display()))?;
+    if output.kind != state::OutputKind::Symlink
+        || state::content_hash(symlink_path.as_os_str().as_bytes()) != output.hash
+    {
+        return Ok(());
+    }
+
+    let folded_dir = dest.canonicalize()?;
+    log!(Info, "Unfolding {}", dest.display());
+    session.remove_file(dest, opt)?;
+    session.create_dir(dest, opt)?;
+    session.state().forget(dest);
+
+    let patterns = session
+        .ignores
+        .get(&output.entry)
+        .map(Vec::as_slice)
+        .unwrap_or_default();
+    let rules = ignore::IgnoreRules::new(&folded_dir, patterns)?;
+    relink_folded(
+        &output.entry,
+        &folded_dir,
+        dest,
+        &rules,
+        relative,
+        opt,
+        session,
+    )
+}
+
+/// Links the children of `dir` that a folded symlink used to cover, the way
+/// symlink_dir_all would have: ignored files are left out and directories
+/// containing them are walked.
+fn relink_folded(
+    name: &str,
+    dir: &path::Path,
+    dest: &path::Path,
+    rules: &ignore::IgnoreRules,
+    relative: bool,
+    opt: &LinkOpt,
+    session: &Session,
+) -> Result<()> {
+    let rules = rules.descend(dir)?;
+    for child in fs::read_dir(dir)? {
+        let child = child?;
+        let is_dir = child.path().is_dir();
+        if rules.is_ignored(&child.path(), is_dir) {
+            continue;
+        }
+        let child_dest = dest.join(child.file_name());
+        if is_dir && contains_ignored(&child.path(), &rules.descend(&child.path())?)? {
+            session.create_dir(&child_dest, opt)?;
+            relink_folded(
+                name,
+                &child.path(),
+                &child_dest,
+                &rules,
+                relative,
+                opt,
+                session,
+            )?;
+        } else if opt.dry {
+            log!(
+                Info,
+                "Symlinked {} -> {}",
+                child.path().display(),
+                child_dest.display()
+            );
+        } else {
+            symlink_file(
+                name,
+                child.path(),
+                &child_dest,
+                relative,
+                None,
+                opt,
+                session,
+            )?;
+        }
     }
     Ok(())
 }
 
+/// Moves a conflicting destination out of the way as requested by `--backup`
+/// or `--force`. Returns false if the conflict has to be resolved manually.
+fn clear_conflict(
+    dest: &path::Path,
+    target: &path::Path,
+    opt: &LinkOpt,
+    session: &Session,
+) -> Result<bool> {
+    if opt.interactive {
+        return resolve_conflict(dest, target, opt, session);
+    }
+    if opt.backup {
+        log!(
+            Warning,
+            "Destination {} already exists. Backing up",
+            dest.display()
+        );
+        session.stash(dest, opt)?;
+    } else if opt.force {
+        log!(
+            Warning,
+            "Destination {} already exists. Removing",
+            dest.display()
+        );
+        session.remove_all(dest, opt)?;
+    } else {
+        return Ok(false);
+    }
+    Ok(true)
+}
+
+/// Asks what to do about `dest` until a valid answer is given. Running out of
+/// input counts as skipping.
+fn resolve_conflict(
+    dest: &path::Path,
+    target: &path::Path,
+    opt: &LinkOpt,
+    session: &Session,
+) -> Result<bool> {
+    loop {
+        print!(
+            "Destination {} already exists. [s]kip, [o]verwrite, [b]ackup, [d]iff, [a]dopt? ",
+            dest.display()
+        );
+        io::stdout().flush()?;
+        let mut answer = String::new();
+        if io::stdin().read_line(&mut answer)? == 0 {
+            println!();
+            return Ok(false);
+        }
+
+        match answer.trim() {
+            "s" | "skip" => return Ok(false),
+            "o" | "overwrite" => {
+                log!(Warning, "Removing {}", dest.display());
+                session.remove_all(dest, opt)?;
+                return Ok(true);
+            }
+            "b" | "backup" => {
+                session.stash(dest, opt)?;
+                return Ok(true);
+            }
+            "d" | "diff" => {
+                if dest.is_dir() || target.is_dir() {
+                    log!(Warning, "Can not diff directories");
+                    continue;
+                }
+                let contents = fs::read(target)
+                    .map_err(|err| format!("could not read file {}: {err}", target.display()))?;
+                if !diff::print_file_diff(dest, &String::from_utf8_lossy(&contents))? {
+                    log!(Info, "Destination {} is identical", dest.display());
+                }
+            }
+            "a" | "adopt" => {
+                if dest.is_symlink() {
+                    log!(Warning, "Can not adopt a symlink");
+                    continue;
+                }
+                if opt.privileged {
+                    log!(Warning, "Can not adopt files of privileged entries");
+                    continue;
+                }
+                // The tracked file is backed up rather than deleted, in case
+                // adopting was a mistake.
+                session.stash(
+                    target,
+                    &LinkOpt {
+                        dry: opt.dry,
+                        ..Default::default()
+                    },
+                )?;
+                if !opt.dry {
+                    backup::move_path(dest, target)?;
+                    if let Some(journal) = session.journal().as_mut() {
+                        journal.moved(dest, target);
+                    }
+                }
+                log!(Info, "Adopted {} as {}", dest.display(), target.display());
+                return Ok(true);
+            }
+            answer => log!(Warning, "Invalid answer: {answer}"),
+        }
+    }
+}
+
 fn symlink_file(
+    name: &str,
     target: impl AsRef<path::Path>,
     dest: impl AsRef<path::Path>,
-    force: bool,
-    dry: bool,
+    relative: bool,
+    dir_mode: Option<u32>,
+    opt: &LinkOpt,
+    session: &Session,
 ) -> Result<()> {
     let target = target.as_ref();
     let dest = dest.as_ref();
 
+    // Relative symlinks are resolved from the real directory holding them,
+    // so the path is computed from the canonical parent of dest.
+    let symlink_path = if relative {
+        let dest_parent = dest
+            .parent()
+            .ok_or(format!("could not access parent dir of {}", dest.display()))?;
+        relative_path(&canonicalize_existing(dest_parent)?, target)
+    } else {
+        target.to_path_buf()
+    };
+
     if dest.exists() {
-        if force {
-            log!(
-                Warning,
-                "Destination {} already exists. Removing",
-                dest.display()
-            );
-            if !dry {
-                fs::remove_file(dest)
-                    .map_err(|err| format!("could not remove file {}: {err}", dest.display()))?;
-            }
-        } else if dest.is_symlink() {
+        if dest.is_symlink() {
             let symlink_origin = dest.canonicalize()?;
             if target.canonicalize()? == symlink_origin {
-                log!(Info, "Symlink up-to-date: {}", dest.display());
-            } else {
+                if fs::read_link(dest)? == symlink_path {
+                    log!(Info, "Symlink up-to-date: {}", dest.display());
+                    session.state().record(
+                        name,
+                        state::OutputKind::Symlink,
+                        target,
+                        dest,
+                        symlink_path.as_os_str().as_bytes(),
+                        opt.privileged,
+                    );
+                    return Ok(());
+                }
+                log!(
+                    Info,
+                    "Replacing symlink {} with {} one",
+                    dest.display(),
+                    if relative {
+                        "a relative"
+                    } else {
+                        "an absolute"
+                    }
+                );
+                session.remove_file(dest, opt)?;
+            } else if !clear_conflict(dest, target, opt, session)? {
                 log!(
                     Warning,
                     "Destination {} is symlinked to {}. Resolve manually.",
                     dest.display(),
                     symlink_origin.display()
                 );
+                return Ok(());
             }
-            return Ok(());
-        } else {
+        } else if !clear_conflict(dest, target, opt, session)? {
             log!(
                 Warning,
                 "Destination {} exists but it's not a symlink. Resolve manually",
@@ -424,43 +1974,920 @@ fn symlink_file(
             "Destination {} is a broken symlink. Ignoring",
             dest.display()
         );
-        if !dry {
-            fs::remove_file(dest)
-                .map_err(|err| format!("could not remove file {}: {err}", dest.display()))?;
-        }
-    } else if !dry {
-        let dest_parent = dest
-            .parent()
-            .ok_or(format!("could not access parent dir of {}", dest.display()))?;
-        fs::create_dir_all(dest_parent)
-            .map_err(|err| format!("could not create dir {}: {err}", dest_parent.display()))?;
+        session.remove_file(dest, opt)?;
+    } else {
+        create_parent_dirs(dest, dir_mode, opt, session)?;
     }
 
-    if !dry {
-        symlink_unix(target, dest).map_err(|err| {
-            format!(
-                "could not symlink {} to {}: {err}",
-                target.display(),
+    session.symlink(&symlink_path, dest, opt)?;
+
+    log!(Info, "Symlinked {} -> {}", target.display(), dest.display());
+    session.state().record(
+        name,
+        state::OutputKind::Symlink,
+        target,
+        dest,
+        symlink_path.as_os_str().as_bytes(),
+        opt.privileged,
+    );
+    Ok(())
+}
+
+/// Path to `to` as seen from the directory `from`, both being absolute.
+fn relative_path(from: &path::Path, to: &path::Path) -> path::PathBuf {
+    let from: Vec<_> = from.components().collect();
+    let to: Vec<_> = to.components().collect();
+    let common = from.iter().zip(&to).take_while(|(a, b)| a == b).count();
+
+    let mut relative = path::PathBuf::new();
+    for _ in common..from.len() {
+        relative.push("..");
+    }
+    for component in &to[common..] {
+        relative.push(component);
+    }
+    relative
+}
+
+/// Like `canonicalize`, but tolerates trailing components that do not exist
+/// yet, e.g. directories that a dry run would have created.
+fn canonicalize_existing(the_path: &path::Path) -> Result<path::PathBuf> {
+    for ancestor in the_path.ancestors() {
+        if let Ok(canonical) = ancestor.canonicalize() {
+            let rest = the_path
+                .strip_prefix(ancestor)
+                .map_err(|err| format!("invalid path {}: {err}", the_path.display()))?;
+            return Ok(canonical.join(rest));
+        }
+    }
+    Err(format!("could not find {}", the_path.display()).into())
+}
+
+fn copy_file(
+    name: &str,
+    entry: &Entry,
+    target: impl AsRef<path::Path>,
+    dest: impl AsRef<path::Path>,
+    opt: &LinkOpt,
+    session: &Session,
+) -> Result<()> {
+    let target = target.as_ref();
+    let dest = dest.as_ref();
+    let permissions = entry.permissions()?;
+    let contents = fs::read(target)
+        .map_err(|err| format!("could not read file {}: {err}", target.display()))?;
+
+    if dest.is_symlink() && dest.canonicalize().ok().as_deref() == Some(target) {
+        log!(Info, "Replacing symlink {} with a copy", dest.display());
+        session.remove_file(dest, opt)?;
+    } else if dest.is_file() && !dest.is_symlink() {
+        let current = fs::read(dest)
+            .map_err(|err| format!("could not read file {}: {err}", dest.display()))?;
+        if current == contents {
+            log!(Info, "Copy up-to-date: {}", dest.display());
+            apply_permissions(dest, permissions, true, opt, session)?;
+            session.state().record(
+                name,
+                state::OutputKind::Copy,
+                target,
+                dest,
+                &contents,
+                opt.privileged,
+            );
+            return Ok(());
+        }
+
+        // The hash of what was copied last time tells an outdated copy apart
+        // from one that was edited in place.
+        let recorded = session.state().get(dest).cloned();
+        match recorded {
+            Some(output)
+                if output.kind == state::OutputKind::Copy
+                    && output.hash == state::content_hash(&current) =>
+            {
+                log!(Info, "Source {} changed. Updating copy", target.display());
+                apply_permissions(dest, permissions, true, opt, session)?;
+            }
+            Some(output) if output.kind == state::OutputKind::Copy => {
+                if !clear_conflict(dest, target, opt, session)? {
+                    log!(
+                        Warning,
+                        "Destination {} was edited since it was copied. Resolve manually",
+                        dest.display()
+                    );
+                    return Ok(());
+                }
+            }
+            _ => {
+                if !clear_conflict(dest, target, opt, session)? {
+                    log!(
+                        Warning,
+                        "Destination {} exists but it's not a copy of {}. Resolve manually",
+                        dest.display(),
+                        target.display()
+                    );
+                    return Ok(());
+                }
+            }
+        }
+    } else if dest.exists() || dest.is_symlink() {
+        if !clear_conflict(dest, target, opt, session)? {
+            log!(
+                Warning,
+                "Destination {} exists but it's not a file. Resolve manually",
                 dest.display()
+            );
+            return Ok(());
+        }
+    } else {
+        create_parent_dirs(dest, entry.dir_permissions()?, opt, session)?;
+    }
+
+    // Adopting the destination replaces the target, so it is read again.
+    let contents = fs::read(target)
+        .map_err(|err| format!("could not read file {}: {err}", target.display()))?;
+    // Like `cp`, the copy gets the permissions of the target unless others
+    // are declared.
+    let mode = match permissions {
+        Some(mode) => mode,
+        None => {
+            fs::metadata(target)
+                .map_err(|err| format!("could not access {}: {err}", target.display()))?
+                .permissions()
+                .mode()
+                & 0o7777
+        }
+    };
+    session.write(dest, &contents, Some(mode), opt)?;
+    log!(Info, "Copied {} -> {}", target.display(), dest.display());
+    session.state().record(
+        name,
+        state::OutputKind::Copy,
+        target,
+        dest,
+        &contents,
+        opt.privileged,
+    );
+    Ok(())
+}
+
+fn hardlink_file(
+    name: &str,
+    target: impl AsRef<path::Path>,
+    dest: impl AsRef<path::Path>,
+    dir_mode: Option<u32>,
+    opt: &LinkOpt,
+    session: &Session,
+) -> Result<()> {
+    let target = target.as_ref();
+    let dest = dest.as_ref();
+    let contents = fs::read(target)
+        .map_err(|err| format!("could not read file {}: {err}", target.display()))?;
+
+    if dest.is_symlink() && dest.canonicalize().ok().as_deref() == Some(target) {
+        log!(Info, "Replacing symlink {} with a hardlink", dest.display());
+        session.remove_file(dest, opt)?;
+    } else if dest.exists() || dest.is_symlink() {
+        if !dest.is_symlink() && is_same_file(target, dest)? {
+            log!(Info, "Hardlink up-to-date: {}", dest.display());
+            session.state().record(
+                name,
+                state::OutputKind::Hardlink,
+                target,
+                dest,
+                &contents,
+                opt.privileged,
+            );
+            return Ok(());
+        }
+
+        // Programs that save by writing a new file and renaming it over the
+        // old one silently break the hardlink.
+        let replaced = session
+            .state()
+            .get(dest)
+            .is_some_and(|output| output.kind == state::OutputKind::Hardlink);
+        if !clear_conflict(dest, target, opt, session)? {
+            if replaced {
+                log!(
+                    Warning,
+                    "Destination {} was replaced since it was hardlinked. Resolve manually",
+                    dest.display()
+                );
+            } else {
+                log!(
+                    Warning,
+                    "Destination {} exists but it's not a hardlink to {}. Resolve manually",
+                    dest.display(),
+                    target.display()
+                );
+            }
+            return Ok(());
+        }
+    } else {
+        create_parent_dirs(dest, dir_mode, opt, session)?;
+    }
+
+    session.hard_link(target, dest, opt)?;
+
+    // Adopting the destination replaces the target, so it is read again.
+    let contents = fs::read(target)
+        .map_err(|err| format!("could not read file {}: {err}", target.display()))?;
+    log!(
+        Info,
+        "Hardlinked {} -> {}",
+        target.display(),
+        dest.display()
+    );
+    session.state().record(
+        name,
+        state::OutputKind::Hardlink,
+        target,
+        dest,
+        &contents,
+        opt.privileged,
+    );
+    Ok(())
+}
+
+fn is_same_file(a: impl AsRef<path::Path>, b: impl AsRef<path::Path>) -> Result<bool> {
+    let (a, b) = (a.as_ref(), b.as_ref());
+    let a_metadata =
+        fs::metadata(a).map_err(|err| format!("could not access {}: {err}", a.display()))?;
+    let b_metadata =
+        fs::metadata(b).map_err(|err| format!("could not access {}: {err}", b.display()))?;
+    Ok(a_metadata.dev() == b_metadata.dev() && a_metadata.ino() == b_metadata.ino())
+}
+
+fn unlink_target(entry: &Entry, target: &path::Path, dry: bool, session: &Session) -> Result<()> {
+    let opt = &LinkOpt {
+        dry,
+        privileged: entry.privileged,
+        ..Default::default()
+    };
+    for (target, dest) in expand_target(entry, target)? {
+        let rules = entry_ignore_rules(entry, &target)?;
+        unlink_dir_all(entry, target, dest, &rules, opt, session)?;
+    }
+    Ok(())
+}
+
+fn unlink_dir_all(
+    entry: &Entry,
+    target: impl AsRef<path::Path>,
+    dest: impl AsRef<path::Path>,
+    rules: &ignore::IgnoreRules,
+    opt: &LinkOpt,
+    session: &Session,
+) -> Result<()> {
+    let target = resolve_home_dir(&target)?
+        .canonicalize()
+        .map_err(|err| format!("could not find {}: {err}", target.as_ref().display()))?;
+    let dest = path::absolute(resolve_home_dir(dest)?)?;
+
+    let folded = entry.fold
+        && dest.is_symlink()
+        && dest
+            .canonicalize()
+            .is_ok_and(|origin| origin.starts_with(&target));
+
+    if target.is_dir() && entry.walks_dirs() && !folded {
+        let rules = rules.descend(&target)?;
+        for child in fs::read_dir(&target)? {
+            let child = child?;
+            if rules.is_ignored(&child.path(), child.path().is_dir()) {
+                continue;
+            }
+            let dest = &dest.join(child.path().file_name().ok_or(format!(
+                "could not extract file_name of {}",
+                child.path().display()
+            ))?);
+            unlink_dir_all(entry, child.path(), dest, &rules, opt, session)?;
+        }
+        remove_created_dirs(&dest, opt, session)?;
+    } else if entry.mode == InstallMode::Symlink {
+        unlink_file(&target, &dest, opt, session)?;
+    } else {
+        // Bound first, the state stays locked for as long as the guard lives.
+        let recorded = session.state().get(&dest).cloned();
+        if let Some(output) = recorded {
+            remove_output(&output, opt, session)?;
+            session.state().forget(&dest);
+        } else if dest.exists() {
+            log!(
+                Warning,
+                "Destination {} was not created by tread. Ignoring",
+                dest.display()
+            );
+        }
+    }
+    Ok(())
+}
+
+fn unlink_file(
+    target: impl AsRef<path::Path>,
+    dest: impl AsRef<path::Path>,
+    opt: &LinkOpt,
+    session: &Session,
+) -> Result<()> {
+    let target = target.as_ref();
+    let dest = dest.as_ref();
+
+    if !dest.is_symlink() {
+        if dest.exists() {
+            log!(
+                Warning,
+                "Destination {} exists but it's not a symlink. Ignoring",
+                dest.display()
+            );
+        }
+        return Ok(());
+    }
+
+    match dest.canonicalize() {
+        Ok(symlink_origin) if symlink_origin.starts_with(target) => {
+            session.remove_file(dest, opt)?;
+            log!(Info, "Unlinked {}", dest.display());
+            session.state().forget(dest);
+            if let Some(parent) = dest.parent() {
+                remove_created_dirs(parent, opt, session)?;
+            }
+        }
+        Ok(symlink_origin) => {
+            log!(
+                Warning,
+                "Destination {} is symlinked to {}. Ignoring",
+                dest.display(),
+                symlink_origin.display()
+            );
+        }
+        Err(_) => {
+            log!(
+                Warning,
+                "Destination {} is a broken symlink. Ignoring",
+                dest.display()
+            );
+        }
+    }
+    Ok(())
+}
+
+/// Removes `dir` and its parents for as long as tread created them and
+/// nothing else lives in them. The batch only removes privileged ones that
+/// are empty by the time it runs.
+fn remove_created_dirs(dir: &path::Path, opt: &LinkOpt, session: &Session) -> Result<()> {
+    let mut parent = Some(dir);
+    while let Some(dir) = parent {
+        let created = session.state().is_created_dir(dir);
+        if !created || !dir.is_dir() || dir.is_symlink() {
+            break;
+        }
+        if opt.privileged && !opt.dry {
+            session.remove_dir(dir, opt)?;
+            log!(Info, "Removing dir {} if empty", dir.display());
+        } else if fs::read_dir(dir)
+            .map_err(|err| format!("could not read dir {}: {err}", dir.display()))?
+            .next()
+            .is_none()
+        {
+            session.remove_dir(dir, opt)?;
+            log!(Info, "Removed empty dir {}", dir.display());
+        } else {
+            break;
+        }
+        parent = dir.parent();
+    }
+    Ok(())
+}
+
+fn prune_outputs(manifest: &Manifest, dry: bool, session: &Session) -> Result<()> {
+    let mut declared = HashSet::new();
+    for (name, entries) in manifest.entries.iter() {
+        // Outputs of entries that don't apply here are as good as undeclared.
+        for entry in entries.iter().filter(|entry| entry.applies()) {
+            if let Some(target) = &entry.target {
+                let mut links = Vec::new();
+                collect_target(entry, target, &mut links).map_err(|err| {
+                    format!(
+                        "something went wrong while resolving {}:\n    {err}",
+                        entry.label(name)
+                    )
+                })?;
+                declared.extend(links.into_iter().map(|(_, dest)| dest));
+            }
+            if entry.template.is_some() {
+                declared.insert(path::absolute(resolve_home_dir(&entry.dest)?)?);
+            }
+        }
+    }
+
+    let orphans: Vec<state::Output> = session
+        .state()
+        .owned()
+        .filter(|output| !declared.contains(&output.dest))
+        .cloned()
+        .collect();
+    if orphans.is_empty() {
+        log!(Info, "Nothing to prune.");
+    }
+    for output in orphans {
+        let opt = &LinkOpt {
+            dry,
+            privileged: output.privileged,
+            ..Default::default()
+        };
+        remove_output(&output, opt, session).map_err(|err| {
+            format!(
+                "something went wrong while pruning {}:\n    {err}",
+                output.entry
             )
         })?;
+        if !dry {
+            session.state().forget(&output.dest);
+        }
     }
+    Ok(())
+}
 
-    log!(Info, "Symlinked {} -> {}", target.display(), dest.display());
+fn collect_target(
+    entry: &Entry,
+    target: &path::Path,
+    links: &mut Vec<(path::PathBuf, path::PathBuf)>,
+) -> Result<()> {
+    for (target, dest) in expand_target(entry, target)? {
+        let rules = entry_ignore_rules(entry, &target)?;
+        collect_links(entry, target, dest, &rules, links)?;
+    }
     Ok(())
 }
 
-fn generate_template(
+/// Pairs of target and destination symlink_dir_all would install.
+fn collect_links(
+    entry: &Entry,
+    target: impl AsRef<path::Path>,
     dest: impl AsRef<path::Path>,
+    rules: &ignore::IgnoreRules,
+    links: &mut Vec<(path::PathBuf, path::PathBuf)>,
+) -> Result<()> {
+    let target = resolve_home_dir(&target)?
+        .canonicalize()
+        .map_err(|err| format!("could not find {}: {err}", target.as_ref().display()))?;
+    let dest = path::absolute(resolve_home_dir(dest)?)?;
+
+    if target.is_dir() && entry.walks_dirs() {
+        let rules = rules.descend(&target)?;
+
+        // Folded entries own a single symlink for the whole directory.
+        if entry.fold
+            && entry.mode == InstallMode::Symlink
+            && (dest.is_symlink() || (!dest.exists() && !contains_ignored(&target, &rules)?))
+        {
+            links.push((target, dest));
+            return Ok(());
+        }
+
+        for child in fs::read_dir(&target)? {
+            let child = child?;
+            if rules.is_ignored(&child.path(), child.path().is_dir()) {
+                continue;
+            }
+            let dest = &dest.join(child.path().file_name().ok_or(format!(
+                "could not extract file_name of {}",
+                child.path().display()
+            ))?);
+            collect_links(entry, child.path(), dest, &rules, links)?;
+        }
+    } else {
+        links.push((target, dest));
+    }
+    Ok(())
+}
+
+fn remove_output(output: &state::Output, opt: &LinkOpt, session: &Session) -> Result<()> {
+    let dest = &output.dest;
+    if !dest.exists() && !dest.is_symlink() {
+        log!(Info, "Already removed: {}", dest.display());
+        return Ok(());
+    }
+
+    // Only remove what is still exactly as tread left it.
+    let untouched = match output.kind {
+        state::OutputKind::Symlink => {
+            dest.is_symlink()
+                && state::content_hash(
+                    fs::read_link(dest)
+                        .map_err(|err| format!("could not read symlink {}: {err}", dest.display()))?
+                        .as_os_str()
+                        .as_bytes(),
+                ) == output.hash
+        }
+        state::OutputKind::Hardlink => {
+            !dest.is_symlink() && output.source.exists() && is_same_file(&output.source, dest)?
+        }
+        state::OutputKind::Template | state::OutputKind::Copy => {
+            !dest.is_symlink()
+                && dest.is_file()
+                && state::content_hash(
+                    &fs::read(dest)
+                        .map_err(|err| format!("could not read file {}: {err}", dest.display()))?,
+                ) == output.hash
+        }
+    };
+    if !untouched {
+        log!(
+            Warning,
+            "Destination {} was modified outside of tread. Refusing to remove",
+            dest.display()
+        );
+        return Ok(());
+    }
+
+    session.remove_file(dest, opt)?;
+    log!(Info, "Removed {}", dest.display());
+    if let Some(parent) = dest.parent() {
+        remove_created_dirs(parent, opt, session)?;
+    }
+    Ok(())
+}
+
+/// Moves an existing file or directory into the dotfiles next to the
+/// manifest, appends an entry for it and links it back into place.
+fn adopt(
+    manifest: &Manifest,
+    the_path: &path::Path,
+    name: Option<&str>,
+    opt: &LinkOpt,
+    session: &Session,
+) -> Result<()> {
+    if the_path.is_symlink() {
+        return Err(format!("{} is already a symlink", the_path.display()).into());
+    }
+    let canonical_path = the_path
+        .canonicalize()
+        .map_err(|err| format!("could not find {}: {err}", the_path.display()))?;
+
+    let name = match name {
+        Some(name) => name.to_string(),
+        None => the_path
+            .file_name()
+            .ok_or(format!(
+                "could not extract file_name of {}",
+                the_path.display()
+            ))?
+            .to_string_lossy()
+            .trim_start_matches('.')
+            .to_string(),
+    };
+    if name.is_empty() {
+        return Err(format!("could not derive a name from {}", the_path.display()).into());
+    }
+    if manifest.entries.contains_key(&name) {
+        return Err(format!("entry {name} already exists, choose another one with --name").into());
+    }
+
+    let manifest_dir = manifest.path.parent().ok_or(format!(
+        "could not access parent dir of {}",
+        manifest.path.display()
+    ))?;
+    if canonical_path.starts_with(manifest_dir) {
+        return Err(format!(
+            "{} is already inside {}",
+            the_path.display(),
+            manifest_dir.display()
+        )
+        .into());
+    }
+    let repo_path = manifest_dir.join(&name);
+    if repo_path.exists() || repo_path.is_symlink() {
+        return Err(format!("{} already exists", repo_path.display()).into());
+    }
+
+    let home_dir = resolve_home_dir("~")?;
+    let dest = match the_path.strip_prefix(&home_dir) {
+        Ok(rest) => path::Path::new("~").join(rest),
+        Err(_) => the_path.to_path_buf(),
+    };
+
+    if !opt.dry {
+        backup::move_path(the_path, &repo_path)?;
+    }
+    log!(
+        Info,
+        "Moved {} -> {}",
+        the_path.display(),
+        repo_path.display()
+    );
+
+    // Appending keeps the formatting and comments of the manifest intact.
+    let original_data = fs::read_to_string(&manifest.path)
+        .map_err(|err| format!("could not read file {}: {err}", manifest.path.display()))?;
+    let mut manifest_data = original_data.clone();
+    if !manifest_data.is_empty() && !manifest_data.ends_with('\n') {
+        manifest_data.push('\n');
+    }
+    let name_key = if name
+        .chars()
+        .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+    {
+        name.clone()
+    } else {
+        toml::Value::String(name.clone()).to_string()
+    };
+    manifest_data.push_str(&format!(
+        "\n[[entries.{name_key}]]\ntarget = {}\ndest = {}\n",
+        toml::Value::String(name.clone()),
+        toml::Value::String(dest.display().to_string()),
+    ));
+    if opt.dry {
+        log!(Info, "Added entry {name} to {}", manifest.path.display());
+        log!(
+            Info,
+            "Symlinked {} -> {}",
+            repo_path.display(),
+            the_path.display()
+        );
+        return Ok(());
+    }
+
+    // Without the entry or the symlink the moved file would be stranded in the
+    // dotfiles, so it goes back where it came from.
+    if let Err(err) = fs::write(&manifest.path, manifest_data) {
+        let err = format!("could not write to {}: {err}", manifest.path.display());
+        undo_adopt(None, the_path, &repo_path)
+            .map_err(|undo_err| format!("{err}\n    {undo_err}"))?;
+        return Err(err.into());
+    }
+    log!(Info, "Added entry {name} to {}", manifest.path.display());
+
+    let linked = repo_path
+        .canonicalize()
+        .map_err(|err| err.into())
+        .and_then(|target| symlink_file(&name, target, the_path, opt.relative, None, opt, session));
+    if let Err(err) = linked {
+        undo_adopt(Some((manifest, &original_data)), the_path, &repo_path)
+            .map_err(|undo_err| format!("{err}\n    {undo_err}"))?;
+        return Err(err);
+    }
+    Ok(())
+}
+
+/// Moves an adopted path back, after restoring the manifest to
+/// `original_data` if it was already changed.
+fn undo_adopt(
+    manifest: Option<(&Manifest, &str)>,
+    the_path: &path::Path,
+    repo_path: &path::Path,
+) -> Result<()> {
+    if let Some((manifest, original_data)) = manifest {
+        fs::write(&manifest.path, original_data)
+            .map_err(|err| format!("could not write to {}: {err}", manifest.path.display()))?;
+        log!(
+            Warning,
+            "Removed the entry from {}",
+            manifest.path.display()
+        );
+    }
+    if the_path.is_symlink() {
+        fs::remove_file(the_path)
+            .map_err(|err| format!("could not remove symlink {}: {err}", the_path.display()))?;
+    }
+    backup::move_path(repo_path, the_path)?;
+    log!(
+        Warning,
+        "Moved {} back to {}",
+        repo_path.display(),
+        the_path.display()
+    );
+    Ok(())
+}
+
+fn generate_template(
+    name: &str,
+    entry: &Entry,
     template: impl AsRef<path::Path>,
     context: &TemplateContext,
-    template_engine: &mut upon::Engine,
+    template_engine: &upon::Engine,
     dry: bool,
+    session: &Session,
+) -> Result<bool> {
+    let opt = &LinkOpt {
+        dry,
+        privileged: entry.privileged,
+        ..Default::default()
+    };
+    let (template, rendered) = render_template(template, context, template_engine)?;
+    let dest = path::absolute(resolve_home_dir(&entry.dest)?)?;
+    unfold_ancestors(&dest, entry.relative.unwrap_or(false), opt, session)?;
+    let existed = dest.exists();
+    let permissions = entry.permissions()?;
+    // Before writing, which would hide that they drifted.
+    if existed {
+        apply_permissions(&dest, permissions, true, opt, session)?;
+    }
+
+    // Rewriting identical output would only bump the mtime and wake up
+    // whatever watches the file.
+    let unchanged = existed
+        && fs::read(write_destination(&dest)).is_ok_and(|current| current == rendered.as_bytes());
+    if unchanged {
+        log!(Info, "Template unchanged: {}", dest.display());
+    } else {
+        create_parent_dirs(&dest, entry.dir_permissions()?, opt, session)?;
+        session.write(&dest, rendered.as_bytes(), permissions, opt)?;
+        if existed {
+            log!(Info, "Template updated: {}", dest.display());
+        } else {
+            log!(Info, "Template generated: {}", dest.display());
+        }
+    }
+
+    session.state().record(
+        name,
+        state::OutputKind::Template,
+        &template,
+        &dest,
+        rendered.as_bytes(),
+        opt.privileged,
+    );
+    Ok(!unchanged)
+}
+
+/// Logs which entries got their templates rewritten.
+fn report_templates(templates: &[(String, bool)]) {
+    if templates.is_empty() {
+        return;
+    }
+    let mut changed: Vec<&str> = templates
+        .iter()
+        .filter(|(_, changed)| *changed)
+        .map(|(name, _)| name.as_str())
+        .collect();
+    changed.sort();
+    changed.dedup();
+    if changed.is_empty() {
+        log!(Info, "All templates are unchanged.");
+    } else {
+        log!(Info, "Templates changed in: {}", changed.join(", "));
+    }
+}
+
+/// Writes to a temporary file next to `dest` and renames it over `dest`, so
+/// readers never see a partially written file. The file gets `mode` from the
+/// start, an existing `dest` otherwise keeps its permissions. It also keeps
+/// its owner, and a symlinked one is written through.
+fn write_atomic(dest: &path::Path, contents: &[u8], mode: Option<u32>) -> Result<()> {
+    let dest = &write_destination(dest);
+    let tmp_path = tmp_path(dest)?;
+    let existing = fs::metadata(dest).ok();
+    let mode = mode.or(existing
+        .as_ref()
+        .map(|metadata| metadata.permissions().mode() & 0o7777));
+
+    let written = (|| -> io::Result<()> {
+        // A leftover of an interrupted write would keep its own permissions.
+        if tmp_path.is_symlink() || tmp_path.exists() {
+            fs::remove_file(&tmp_path)?;
+        }
+        let mut file = fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .mode(mode.unwrap_or(0o666))
+            .open(&tmp_path)?;
+        // The umask only applies when no mode was asked for.
+        if let Some(mode) = mode {
+            file.set_permissions(fs::Permissions::from_mode(mode))?;
+        }
+        if let Some(existing) = &existing {
+            let metadata = file.metadata()?;
+            if (metadata.uid(), metadata.gid()) != (existing.uid(), existing.gid()) {
+                fchown(&file, Some(existing.uid()), Some(existing.gid()))?;
+            }
+        }
+        file.write_all(contents)?;
+        file.sync_all()?;
+        fs::rename(&tmp_path, dest)
+    })();
+    if let Err(err) = written {
+        let _ = fs::remove_file(&tmp_path);
+        return Err(format!("could not write to {}: {err}", dest.display()).into());
+    }
+
+    // The rename only survives a crash once the directory is on disk too.
+    let dest_parent = dest
+        .parent()
+        .ok_or(format!("could not access parent dir of {}", dest.display()))?;
+    fs::File::open(dest_parent)
+        .and_then(|dir| dir.sync_all())
+        .map_err(|err| format!("could not sync dir {}: {err}", dest_parent.display()).into())
+}
+
+/// The file writing to `dest` changes: the file a symlink points to rather
+/// than the symlink itself.
+fn write_destination(dest: &path::Path) -> path::PathBuf {
+    if !dest.is_symlink() {
+        return dest.to_path_buf();
+    }
+    match dest.canonicalize() {
+        Ok(resolved) => resolved,
+        // A dangling symlink, writing creates the file it points to.
+        Err(_) => match (fs::read_link(dest), dest.parent()) {
+            (Ok(original), Some(dest_parent)) => dest_parent.join(original),
+            _ => dest.to_path_buf(),
+        },
+    }
+}
+
+/// The temporary file next to `dest` that is renamed over it.
+fn tmp_path(dest: &path::Path) -> Result<path::PathBuf> {
+    let file_name = dest
+        .file_name()
+        .ok_or(format!("could not extract file_name of {}", dest.display()))?;
+    let mut tmp_name = ffi::OsString::from(".");
+    tmp_name.push(file_name);
+    tmp_name.push(".tread-tmp");
+    Ok(dest.with_file_name(tmp_name))
+}
+
+/// Creates the missing parent dirs of `dest`, giving the new ones `mode`.
+fn create_parent_dirs(
+    dest: &path::Path,
+    mode: Option<u32>,
+    opt: &LinkOpt,
+    session: &Session,
 ) -> Result<()> {
+    let dest_parent = dest
+        .parent()
+        .ok_or(format!("could not access parent dir of {}", dest.display()))?;
+    let missing: Vec<path::PathBuf> = dest_parent
+        .ancestors()
+        .take_while(|dir| !dir.exists())
+        .map(path::Path::to_path_buf)
+        .collect();
+    if missing.is_empty() {
+        return Ok(());
+    }
+
+    session.create_dir_all(dest_parent, opt)?;
+    if let Some(mode) = mode {
+        for dir in missing {
+            session.set_permissions(&dir, mode, opt)?;
+        }
+    }
+    Ok(())
+}
+
+/// Gives an output its declared permission bits. On an output that was
+/// already in place, differing bits are reported as drift.
+fn apply_permissions(
+    the_path: &path::Path,
+    mode: Option<u32>,
+    existed: bool,
+    opt: &LinkOpt,
+    session: &Session,
+) -> Result<()> {
+    let Some(mode) = mode else {
+        return Ok(());
+    };
+
+    // Missing during a dry run, or until the batch of a privileged entry ran.
+    let current = fs::metadata(the_path)
+        .ok()
+        .map(|metadata| metadata.permissions().mode() & 0o7777);
+    if current == Some(mode) {
+        return Ok(());
+    }
+    if let Some(current) = current.filter(|_| existed) {
+        log!(
+            Warning,
+            "Permissions of {} drifted to {current:04o} from {mode:04o}. Resetting",
+            the_path.display()
+        );
+    }
+    session.set_permissions(the_path, mode, opt)
+}
+
+/// Prints what generating the template would change, returning whether
+/// anything would.
+fn diff_template(
+    dest: impl AsRef<path::Path>,
+    template: impl AsRef<path::Path>,
+    context: &TemplateContext,
+    template_engine: &upon::Engine,
+) -> Result<bool> {
+    let (_, rendered) = render_template(template, context, template_engine)?;
+    let dest = path::absolute(resolve_home_dir(dest.as_ref())?)?;
+    diff::print_file_diff(&dest, &rendered)
+}
+
+/// Renders a template in memory, returning its canonical path and output.
+fn render_template(
+    template: impl AsRef<path::Path>,
+    context: &TemplateContext,
+    template_engine: &upon::Engine,
+) -> Result<(path::PathBuf, String)> {
     let template = resolve_home_dir(template.as_ref())?
         .canonicalize()
         .map_err(|err| format!("could not find {}: {err}", template.as_ref().display()))?;
-    let dest = resolve_home_dir(dest.as_ref())?;
 
     let data = fs::read_to_string(&template)
         .map_err(|err| format!("could not read file {}: {err}", template.display()))?;
@@ -471,21 +2898,7 @@ fn generate_template(
         .render(template_engine, context)
         .to_string()
         .map_err(|err| format!("could not render template {}: {err}", template.display()))?;
-
-    let dest_parent = dest
-        .parent()
-        .ok_or(format!("could not access parent dir of {}", dest.display()))?;
-
-    if !dry {
-        if !dest_parent.exists() {
-            fs::create_dir_all(dest_parent)?;
-        }
-        fs::write(&dest, &rendered)
-            .map_err(|err| format!("could not write to {}: {err}", dest.display()))?;
-    }
-
-    log!(Info, "Template generated: {}", template.display());
-    Ok(())
+    Ok((template, rendered))
 }
 
 fn execute_hook(cmd: &str) -> Result<()> {
@@ -497,7 +2910,324 @@ fn execute_hook(cmd: &str) -> Result<()> {
     )
     .args(cmd_iter)
     .output()?;
-    io::stdout().write_all(&output.stdout)?;
-    io::stderr().write_all(&output.stderr)?;
+    print_out(&output.stdout)?;
+    print_err(&output.stderr)?;
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sorted(manifest: &str) -> Result<Vec<String>> {
+        let entries: IndexMap<String, Vec<Entry>> = toml::from_str(manifest).unwrap();
+        let sources = entries
+            .keys()
+            .map(|name| (name.clone(), path::PathBuf::from("Manifest.toml")))
+            .collect();
+        Ok(sort_entries(entries, &sources)?.into_keys().collect())
+    }
+
+    #[test]
+    fn sorts_dependencies_first() {
+        let order = sorted(
+            r#"
+            [[sway]]
+            dest = "~/.config/sway"
+            depends_on = ["fonts", "waybar"]
+            [[waybar]]
+            dest = "~/.config/waybar"
+            depends_on = ["fonts"]
+            [[bash]]
+            dest = "~/.bashrc"
+            [[fonts]]
+            dest = "~/.local/share/fonts"
+            "#,
+        )
+        .unwrap();
+        assert_eq!(order, ["fonts", "waybar", "sway", "bash"]);
+    }
+
+    #[test]
+    fn reports_dependency_cycles() {
+        let err = sorted(
+            r#"
+            [[a]]
+            dest = "a"
+            depends_on = ["b"]
+            [[b]]
+            dest = "b"
+            depends_on = ["c"]
+            [[c]]
+            dest = "c"
+            depends_on = ["a"]
+            "#,
+        )
+        .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "dependency cycle between entries: a -> b -> c -> a"
+        );
+    }
+
+    #[test]
+    fn reports_missing_dependencies() {
+        let err = sorted(
+            r#"
+            [[a]]
+            dest = "a"
+            depends_on = ["b"]
+            "#,
+        )
+        .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "a in Manifest.toml depends on b, which does not exist"
+        );
+    }
+
+    #[test]
+    fn parses_octal_permissions() {
+        assert_eq!(parse_permissions(None).unwrap(), None);
+        assert_eq!(parse_permissions(Some("0600")).unwrap(), Some(0o600));
+        assert_eq!(parse_permissions(Some("755")).unwrap(), Some(0o755));
+        assert_eq!(parse_permissions(Some("4755")).unwrap(), Some(0o4755));
+        for mode in ["", "0800", "rw-r--r--", "17777", "-644"] {
+            assert!(parse_permissions(Some(mode)).is_err(), "{mode}");
+        }
+    }
+
+    #[test]
+    fn finds_relative_paths_between_directories() {
+        let relative =
+            |from: &str, to: &str| relative_path(path::Path::new(from), path::Path::new(to));
+        assert_eq!(
+            relative("/home/me/.config", "/home/me/dots/nvim"),
+            path::Path::new("../dots/nvim")
+        );
+        assert_eq!(
+            relative("/home/me", "/home/me/dots/bashrc"),
+            path::Path::new("dots/bashrc")
+        );
+        assert_eq!(
+            relative("/home/me/.config/sway/conf.d", "/etc/sway"),
+            path::Path::new("../../../../../etc/sway")
+        );
+        assert_eq!(relative("/home/me", "/home/me"), path::Path::new(""));
+    }
+
+    fn manifest(toml: &str) -> Manifest {
+        manifest_at(path::Path::new("/dots/Manifest.toml"), toml)
+    }
+
+    fn manifest_at(the_path: &path::Path, toml: &str) -> Manifest {
+        let mut manifest: Manifest = toml::from_str(toml).unwrap();
+        manifest.path = the_path.to_path_buf();
+        manifest.record_origins(the_path);
+        manifest
+    }
+
+    #[test]
+    fn applies_host_then_profile_overrides() {
+        let hostname = &facts::facts().hostname;
+        let mut manifest = manifest(&format!(
+            r#"
+            [options]
+            theme = "dark"
+            [variables]
+            font = "mono"
+            size = "10"
+            [[entries.bash]]
+            target = "bashrc"
+            dest = "~/.bashrc"
+            [hosts."{hostname}".options]
+            theme = "light"
+            backup = true
+            [hosts."{hostname}".variables]
+            size = "12"
+            [profiles.work.options]
+            theme = "work"
+            [profiles.work.variables]
+            font = "sans"
+            [[profiles.work.entries.bash]]
+            target = "bashrc-work"
+            dest = "~/.bashrc"
+            [[profiles.work.entries.vpn]]
+            target = "vpn"
+            dest = "~/.vpn"
+            "#
+        ));
+        manifest.apply_profiles(Some("work")).unwrap();
+
+        assert_eq!(manifest.options.theme, "work");
+        assert!(manifest.options.backup);
+        let variables = manifest.variables.as_ref().unwrap();
+        assert_eq!(variables["font"], "sans");
+        assert_eq!(variables["size"], "12");
+        assert_eq!(
+            manifest.entries["bash"][0].target.as_deref(),
+            Some(path::Path::new("bashrc-work"))
+        );
+        assert_eq!(manifest.entries["vpn"][0].origin, manifest.path);
+        assert_eq!(manifest.sources["vpn"], manifest.path);
+    }
+
+    #[test]
+    fn rejects_unknown_profiles_and_options() {
+        let mut manifest = manifest("[options]\n[entries]\n[profiles.work.options]\ntheme = 1\n");
+        assert_eq!(
+            manifest
+                .apply_profiles(Some("home"))
+                .unwrap_err()
+                .to_string(),
+            "could not find profile home"
+        );
+        assert!(manifest.apply_profiles(Some("work")).is_err());
+    }
+
+    /// A main manifest with `fragments` next to it, in a directory of its
+    /// own.
+    fn with_fragments(test: &str, fragments: &[(&str, &str)]) -> (Manifest, path::PathBuf) {
+        let dir = env::temp_dir().join(format!("tread-{test}-{}", process::id()));
+        for (file, contents) in fragments {
+            let file = dir.join(file);
+            fs::create_dir_all(file.parent().unwrap()).unwrap();
+            fs::write(file, contents).unwrap();
+        }
+        let dir = dir.canonicalize().unwrap();
+        let manifest = manifest_at(
+            &dir.join("Manifest.toml"),
+            r#"
+            [options]
+            [variables]
+            dots = "/dots"
+            [[entries.bash]]
+            target = "bashrc"
+            dest = "~/.bashrc"
+            "#,
+        );
+        (manifest, dir)
+    }
+
+    #[test]
+    fn merges_fragments_relative_to_themselves() {
+        let (mut manifest, dir) = with_fragments(
+            "include",
+            &[
+                (
+                    "desktop/sway.toml",
+                    r#"
+                    include = ["../shared/*.toml"]
+                    [variables]
+                    bar = "waybar"
+                    [[entries.sway]]
+                    target = "sway"
+                    dest = "~/.config/sway"
+                    [[entries.bar]]
+                    target = "${dots}/${bar}"
+                    dest = "~/.config/${bar}"
+                    "#,
+                ),
+                (
+                    "shared/fonts.toml",
+                    "[[entries.fonts]]\ntarget = \"fonts\"\ndest = \"fonts\"\n",
+                ),
+            ],
+        );
+        let path = manifest.path.clone();
+        manifest
+            .include(
+                &path,
+                &["desktop/*.toml".to_string()],
+                &mut vec![path.clone()],
+            )
+            .unwrap();
+        manifest.interpolate().unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+
+        let sway = &manifest.entries["sway"][0];
+        assert_eq!(sway.origin, dir.join("desktop/sway.toml"));
+        assert_eq!(
+            sway.target.as_deref(),
+            Some(dir.join("desktop/sway").as_path())
+        );
+        assert_eq!(sway.dest, path::Path::new("~/.config/sway"));
+        let bar = &manifest.entries["bar"][0];
+        assert_eq!(bar.target.as_deref(), Some(path::Path::new("/dots/waybar")));
+        let fonts = &manifest.entries["fonts"][0];
+        assert_eq!(fonts.origin, dir.join("shared/fonts.toml"));
+        assert_eq!(fonts.dest, dir.join("shared/fonts"));
+        assert_eq!(manifest.sources["fonts"], fonts.origin);
+        // Entries of the main manifest stay relative to it.
+        assert_eq!(
+            manifest.entries["bash"][0].target.as_deref(),
+            Some(path::Path::new("bashrc"))
+        );
+    }
+
+    #[test]
+    fn rejects_names_defined_twice() {
+        let (mut manifest, dir) = with_fragments(
+            "include-twice",
+            &[
+                (
+                    "entry.toml",
+                    "[[entries.bash]]\ntarget = \"b\"\ndest = \"b\"\n",
+                ),
+                ("variable.toml", "[variables]\ndots = \"/elsewhere\"\n"),
+                ("first.toml", "[variables]\nfont = \"mono\"\n"),
+                ("second.toml", "[variables]\nfont = \"sans\"\n"),
+                ("loop.toml", "include = [\"loop.toml\"]\n"),
+            ],
+        );
+        let path = manifest.path.clone();
+        let mut include = |patterns: &[&str]| {
+            let patterns: Vec<String> =
+                patterns.iter().map(|pattern| pattern.to_string()).collect();
+            manifest
+                .include(&path, &patterns, &mut vec![path.clone()])
+                .unwrap_err()
+                .to_string()
+        };
+
+        assert_eq!(
+            include(&["entry.toml"]),
+            format!(
+                "entry bash in {} is already defined in {}",
+                dir.join("entry.toml").display(),
+                path.display()
+            )
+        );
+        assert_eq!(
+            include(&["variable.toml"]),
+            format!(
+                "variable dots in {} is already defined in {}",
+                dir.join("variable.toml").display(),
+                path.display()
+            )
+        );
+        assert_eq!(
+            include(&["first.toml", "second.toml"]),
+            format!(
+                "variable font in {} is already defined in {}",
+                dir.join("second.toml").display(),
+                dir.join("first.toml").display()
+            )
+        );
+        assert_eq!(
+            include(&["loop.toml"]),
+            format!(
+                "{} is included more than once",
+                dir.join("loop.toml").display()
+            )
+        );
+        assert_eq!(
+            include(&["missing/*.toml"]),
+            format!(
+                "include missing/*.toml in {} did not match any files",
+                path.display()
+            )
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
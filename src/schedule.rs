@@ -0,0 +1,133 @@
+use crate::{
+    helper::{buffer_out, flush_out},
+    lock, Error, Result,
+};
+
+use std::{
+    any::Any,
+    panic::{self, AssertUnwindSafe},
+    sync::{Condvar, Mutex, PoisonError},
+    thread,
+};
+
+struct Queue<T> {
+    started: Vec<bool>,
+    done: Vec<bool>,
+    results: Vec<Option<T>>,
+    error: Option<Error>,
+}
+
+/// Runs `job` for each of `count` entries on up to `jobs` threads and returns
+/// the results in entry order. An entry is only started once all the entries
+/// it `waits_for` are done, and nothing new is started after a failure. A
+/// panicking entry counts as failed. The output of each entry is printed in
+/// one piece once it is done.
+pub fn run<T: Send>(
+    count: usize,
+    waits_for: &[Vec<usize>],
+    jobs: usize,
+    job: impl Fn(usize) -> Result<T> + Sync,
+) -> Result<Vec<T>> {
+    if jobs <= 1 || count <= 1 {
+        return (0..count).map(job).collect();
+    }
+
+    let queue = Mutex::new(Queue {
+        started: vec![false; count],
+        done: vec![false; count],
+        results: (0..count).map(|_| None).collect(),
+        error: None,
+    });
+    let finished = Condvar::new();
+    thread::scope(|scope| {
+        for _ in 0..jobs.min(count) {
+            scope.spawn(|| loop {
+                let index = {
+                    let mut queue = lock(&queue);
+                    loop {
+                        if queue.error.is_some() || queue.started.iter().all(|started| *started) {
+                            return;
+                        }
+                        let ready = (0..count).find(|&index| {
+                            !queue.started[index]
+                                && waits_for[index].iter().all(|&other| queue.done[other])
+                        });
+                        if let Some(index) = ready {
+                            queue.started[index] = true;
+                            break index;
+                        }
+                        queue = finished.wait(queue).unwrap_or_else(PoisonError::into_inner);
+                    }
+                };
+
+                buffer_out();
+                // Otherwise the entries waiting for this one would wait
+                // forever.
+                let result = panic::catch_unwind(AssertUnwindSafe(|| job(index)))
+                    .unwrap_or_else(|payload| Err(panic_message(payload).into()));
+                flush_out();
+
+                let mut queue = lock(&queue);
+                match result {
+                    Ok(value) => {
+                        queue.done[index] = true;
+                        queue.results[index] = Some(value);
+                    }
+                    Err(err) => {
+                        queue.error.get_or_insert(err);
+                    }
+                }
+                finished.notify_all();
+            });
+        }
+    });
+
+    let queue = queue.into_inner().unwrap_or_else(PoisonError::into_inner);
+    if let Some(err) = queue.error {
+        return Err(err);
+    }
+    Ok(queue.results.into_iter().flatten().collect())
+}
+
+fn panic_message(payload: Box<dyn Any + Send>) -> String {
+    let message = match payload.downcast::<String>() {
+        Ok(message) => *message,
+        Err(payload) => payload.downcast::<&str>().map_or_else(
+            |_| "unknown error".to_string(),
+            |message| message.to_string(),
+        ),
+    };
+    format!("panicked: {message}")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn starts_entries_after_the_ones_they_wait_for() {
+        let finished = Mutex::new(Vec::new());
+        let results = run(4, &[vec![1, 2], vec![], vec![1], vec![]], 3, |index| {
+            let finished_before = lock(&finished).clone();
+            lock(&finished).push(index);
+            Ok((index, finished_before))
+        })
+        .unwrap();
+        assert_eq!(
+            results.iter().map(|(index, _)| *index).collect::<Vec<_>>(),
+            [0, 1, 2, 3]
+        );
+        assert!(results[0].1.contains(&1) && results[0].1.contains(&2));
+        assert!(results[2].1.contains(&1));
+    }
+
+    #[test]
+    fn fails_instead_of_hanging_when_an_entry_panics() {
+        let err = run(3, &[vec![], vec![0], vec![1]], 2, |index| match index {
+            0 => panic!("entry 0"),
+            _ => Ok(index),
+        })
+        .unwrap_err();
+        assert_eq!(err.to_string(), "panicked: entry 0");
+    }
+}
@@ -0,0 +1,150 @@
+use crate::{write_atomic, Result};
+
+use serde::{Deserialize, Serialize};
+use std::{
+    env, fs,
+    path::{Path, PathBuf},
+};
+
+/// Everything tread has put on disk, persisted between runs so that it can
+/// tell its own files apart from the ones the user owns.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct State {
+    #[serde(skip)]
+    manifest: PathBuf,
+    #[serde(default)]
+    pub outputs: Vec<Output>,
+    /// Directories tread created for its outputs. Only these are removed
+    /// again once they are empty.
+    #[serde(default)]
+    pub dirs: Vec<PathBuf>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Output {
+    pub dest: PathBuf,
+    pub kind: OutputKind,
+    pub source: PathBuf,
+    pub manifest: PathBuf,
+    pub entry: String,
+    pub hash: String,
+    #[serde(default)]
+    pub privileged: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum OutputKind {
+    Symlink,
+    Copy,
+    Hardlink,
+    Template,
+}
+
+/// `$XDG_STATE_HOME/tread`, falling back to `~/.local/state/tread`.
+pub fn state_dir() -> Result<PathBuf> {
+    let state_dir = if let Ok(dir) = env::var("XDG_STATE_HOME") {
+        PathBuf::from(dir)
+    } else {
+        let home_dir =
+            env::var("HOME").map_err(|err| format!("could not find home directory: {err}"))?;
+        PathBuf::from(home_dir).join(".local/state")
+    };
+    Ok(state_dir.join("tread"))
+}
+
+impl State {
+    pub fn path() -> Result<PathBuf> {
+        Ok(state_dir()?.join("state.toml"))
+    }
+
+    pub fn load(manifest: &Path) -> Result<Self> {
+        let path = Self::path()?;
+        let mut state: State = if path.exists() {
+            toml::from_str(
+                &fs::read_to_string(&path)
+                    .map_err(|err| format!("could not read file {}: {err}", path.display()))?,
+            )
+            .map_err(|err| format!("could not parse toml {}: {err}", path.display()))?
+        } else {
+            State::default()
+        };
+        state.manifest = manifest.to_path_buf();
+        Ok(state)
+    }
+
+    /// Written atomically, so an interrupted run never leaves a truncated
+    /// state behind.
+    pub fn save(&mut self) -> Result<()> {
+        let path = Self::path()?;
+        let state_dir = path
+            .parent()
+            .ok_or(format!("could not access parent dir of {}", path.display()))?;
+        fs::create_dir_all(state_dir)
+            .map_err(|err| format!("could not create dir {}: {err}", state_dir.display()))?;
+
+        self.outputs.sort_by(|a, b| a.dest.cmp(&b.dest));
+        // Removed ones are only gone once a privileged batch has run.
+        self.dirs.retain(|dir| dir.is_dir());
+        self.dirs.sort();
+        let data = toml::to_string(self)
+            .map_err(|err| format!("could not serialize {}: {err}", path.display()))?;
+        write_atomic(&path, data.as_bytes(), None)
+    }
+
+    pub fn record(
+        &mut self,
+        entry: &str,
+        kind: OutputKind,
+        source: &Path,
+        dest: &Path,
+        contents: &[u8],
+        privileged: bool,
+    ) {
+        self.forget(dest);
+        self.outputs.push(Output {
+            dest: dest.to_path_buf(),
+            kind,
+            source: source.to_path_buf(),
+            manifest: self.manifest.clone(),
+            entry: entry.to_string(),
+            hash: content_hash(contents),
+            privileged,
+        });
+    }
+
+    /// Outputs that were created from the currently loaded manifest.
+    pub fn owned(&self) -> impl Iterator<Item = &Output> {
+        self.outputs
+            .iter()
+            .filter(|output| output.manifest == self.manifest)
+    }
+
+    pub fn get(&self, dest: &Path) -> Option<&Output> {
+        self.outputs.iter().find(|output| output.dest == dest)
+    }
+
+    pub fn forget(&mut self, dest: &Path) {
+        self.outputs.retain(|output| output.dest != dest);
+    }
+
+    pub fn created_dir(&mut self, dir: &Path) {
+        if !self.is_created_dir(dir) {
+            self.dirs.push(dir.to_path_buf());
+        }
+    }
+
+    pub fn is_created_dir(&self, dir: &Path) -> bool {
+        self.dirs.iter().any(|created| created == dir)
+    }
+}
+
+/// 64-bit FNV-1a, stable across builds unlike `std`'s `DefaultHasher`.
+pub fn content_hash(contents: &[u8]) -> String {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for byte in contents {
+        hash ^= u64::from(*byte);
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    format!("{hash:016x}")
+}
@@ -0,0 +1,213 @@
+use crate::{
+    collect_target, is_same_file, render_template, resolve_home_dir, state, InstallMode, Manifest,
+    Result, TemplateContext,
+};
+
+use std::{
+    fs,
+    path::{self, Path, PathBuf},
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Status {
+    UpToDate,
+    /// Nothing exists at the destination.
+    Missing,
+    /// A file tread did not create is in the way.
+    Foreign,
+    /// A symlink pointing somewhere other than the target.
+    WrongSymlink,
+    /// A symlink whose target no longer exists.
+    BrokenSymlink,
+    /// The target or template changed since the destination was installed.
+    OutOfDate,
+    /// The destination was edited since it was installed.
+    Modified,
+}
+
+impl Status {
+    fn label(&self) -> &'static str {
+        match self {
+            Status::UpToDate => "up to date",
+            Status::Missing => "missing",
+            Status::Foreign => "foreign file",
+            Status::WrongSymlink => "wrong symlink",
+            Status::BrokenSymlink => "broken symlink",
+            Status::OutOfDate => "out of date",
+            Status::Modified => "modified",
+        }
+    }
+}
+
+pub struct Row {
+    pub entry: String,
+    pub dest: PathBuf,
+    pub status: Status,
+}
+
+/// Classifies every destination of the manifest, or only those of `name`.
+pub fn collect(
+    manifest: &Manifest,
+    name: Option<&str>,
+    context: &TemplateContext,
+    template_engine: &upon::Engine,
+    state: &state::State,
+) -> Result<Vec<Row>> {
+    let mut rows = Vec::new();
+    for (entry_name, entries) in manifest.entries.iter() {
+        if name.is_some_and(|name| name != entry_name) {
+            continue;
+        }
+        for entry in entries.iter().filter(|entry| entry.applies()) {
+            if let Some(target) = &entry.target {
+                let mut links = Vec::new();
+                collect_target(entry, target, &mut links).map_err(|err| {
+                    format!("something went wrong while resolving {entry_name}:\n    {err}")
+                })?;
+                for (target, dest) in links {
+                    rows.push(Row {
+                        entry: entry_name.clone(),
+                        status: link_status(entry.mode, &target, &dest, state)?,
+                        dest,
+                    });
+                }
+            }
+            if let Some(template) = &entry.template {
+                let (_, rendered) =
+                    render_template(template, context, template_engine).map_err(|err| {
+                        format!("something went wrong while generating {entry_name}:\n    {err}")
+                    })?;
+                let dest = path::absolute(resolve_home_dir(&entry.dest)?)?;
+                rows.push(Row {
+                    entry: entry_name.clone(),
+                    status: template_status(&dest, &rendered, state)?,
+                    dest,
+                });
+            }
+        }
+    }
+    rows.sort_by(|a, b| a.entry.cmp(&b.entry).then_with(|| a.dest.cmp(&b.dest)));
+    Ok(rows)
+}
+
+fn link_status(
+    mode: InstallMode,
+    target: &Path,
+    dest: &Path,
+    state: &state::State,
+) -> Result<Status> {
+    if dest.is_symlink() {
+        return Ok(match dest.canonicalize() {
+            Ok(resolved) if mode == InstallMode::Symlink && resolved == target => Status::UpToDate,
+            Ok(_) => Status::WrongSymlink,
+            Err(_) => Status::BrokenSymlink,
+        });
+    }
+    if !dest.exists() {
+        return Ok(Status::Missing);
+    }
+    if dest.is_dir() {
+        return Ok(Status::Foreign);
+    }
+
+    let recorded = state.get(dest);
+    match mode {
+        InstallMode::Symlink => Ok(Status::Foreign),
+        InstallMode::Copy => {
+            let contents = fs::read(dest)
+                .map_err(|err| format!("could not read file {}: {err}", dest.display()))?;
+            let source = fs::read(target)
+                .map_err(|err| format!("could not read file {}: {err}", target.display()))?;
+            Ok(if contents == source {
+                Status::UpToDate
+            } else {
+                edited_status(recorded, state::OutputKind::Copy, &contents)
+            })
+        }
+        InstallMode::Hardlink => {
+            if is_same_file(target, dest)? {
+                return Ok(Status::UpToDate);
+            }
+            let contents = fs::read(dest)
+                .map_err(|err| format!("could not read file {}: {err}", dest.display()))?;
+            Ok(edited_status(
+                recorded,
+                state::OutputKind::Hardlink,
+                &contents,
+            ))
+        }
+    }
+}
+
+fn template_status(dest: &Path, rendered: &str, state: &state::State) -> Result<Status> {
+    if dest.is_symlink() {
+        return Ok(match dest.canonicalize() {
+            Ok(_) => Status::WrongSymlink,
+            Err(_) => Status::BrokenSymlink,
+        });
+    }
+    if !dest.exists() {
+        return Ok(Status::Missing);
+    }
+    if dest.is_dir() {
+        return Ok(Status::Foreign);
+    }
+
+    let contents =
+        fs::read(dest).map_err(|err| format!("could not read file {}: {err}", dest.display()))?;
+    Ok(if contents == rendered.as_bytes() {
+        Status::UpToDate
+    } else {
+        edited_status(state.get(dest), state::OutputKind::Template, &contents)
+    })
+}
+
+/// Tells a stale output apart from one that was edited by hand, by comparing
+/// it with what was recorded when it was written.
+fn edited_status(
+    recorded: Option<&state::Output>,
+    kind: state::OutputKind,
+    contents: &[u8],
+) -> Status {
+    match recorded {
+        Some(output) if output.kind == kind && output.hash == state::content_hash(contents) => {
+            Status::OutOfDate
+        }
+        Some(output) if output.kind == kind => Status::Modified,
+        _ => Status::Foreign,
+    }
+}
+
+pub fn print_table(rows: &[Row]) {
+    let entry_width = rows
+        .iter()
+        .map(|row| row.entry.len())
+        .chain(["ENTRY".len()])
+        .max()
+        .unwrap_or_default();
+    let status_width = rows
+        .iter()
+        .map(|row| row.status.label().len())
+        .chain(["STATUS".len()])
+        .max()
+        .unwrap_or_default();
+
+    println!(
+        "{:entry_width$}  {:status_width$}  DESTINATION",
+        "ENTRY", "STATUS"
+    );
+    for row in rows {
+        // Padded before coloring, escape codes would throw off the width.
+        let label = format!("{:status_width$}", row.status.label());
+        let color = if row.status == Status::UpToDate {
+            "\x1b[0;32m"
+        } else {
+            "\x1b[0;33m"
+        };
+        println!(
+            "{:entry_width$}  {color}{label}\x1b[0m  {}",
+            row.entry,
+            row.dest.display()
+        );
+    }
+}